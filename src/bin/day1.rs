@@ -35,10 +35,7 @@ fn main() -> Result<(), Error> {
     for line in contents.lines() {
         let numbers = line
             .split_whitespace()
-            .filter_map(|candidate| match candidate.parse() {
-                Ok(n) => Some(n),
-                Err(_) => None,
-            })
+            .filter_map(|candidate| candidate.parse().ok())
             .collect::<Vec<usize>>();
 
         if numbers.len() != 2 {
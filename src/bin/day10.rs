@@ -113,7 +113,7 @@ impl TrailMap {
                 match self.walk(step, level + 1, path.clone(), found.clone()) {
                     WalkResult::Final(list) => {
                         found = list.iter().fold(found, |mut acc, elem| {
-                            if !acc.contains(&elem) {
+                            if !acc.contains(elem) {
                                 acc.push(elem.clone());
                             }
                             acc
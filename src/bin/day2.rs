@@ -19,66 +19,561 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use {std::env::args, std::fs::read_to_string, std::io::Error, std::process::exit};
+use std::{collections::BTreeMap, env::args, fmt, fs::read_to_string, io::Error, process::exit};
+
+const USAGE: &str = "Usage: day2 [--config file] [--min-step n] [--max-step n] \
+                     [--direction increasing|decreasing|either] [--allow-equal] \
+                     [--tolerance k] [--explain] [--repair] [--longest] inputfile";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Increasing,
+    Decreasing,
+    Either,
+}
+
+/// The first rule an unsafe report breaks, with the index pair of the offending levels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Violation {
+    TooShort,
+    DirectionChange(usize, usize),
+    WrongDirection(usize, usize),
+    EqualLevels(usize, usize),
+    StepTooSmall(usize, usize),
+    StepTooLarge(usize, usize),
+}
+
+impl Violation {
+    fn pair(&self) -> Option<(usize, usize)> {
+        match *self {
+            Violation::TooShort => None,
+            Violation::DirectionChange(a, b)
+            | Violation::WrongDirection(a, b)
+            | Violation::EqualLevels(a, b)
+            | Violation::StepTooSmall(a, b)
+            | Violation::StepTooLarge(a, b) => Some((a, b)),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Violation::TooShort => "fewer than two levels",
+            Violation::DirectionChange(..) => "direction change",
+            Violation::WrongDirection(..) => "wrong direction",
+            Violation::EqualLevels(..) => "equal levels",
+            Violation::StepTooSmall(..) => "step too small",
+            Violation::StepTooLarge(..) => "step too large",
+        };
+
+        write!(f, "{reason}")
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SafetyPolicy {
+    min_step: i32,
+    max_step: i32,
+    direction: Direction,
+    allow_equal: bool,
+}
+
+impl Default for SafetyPolicy {
+    fn default() -> Self {
+        SafetyPolicy {
+            min_step: 1,
+            max_step: 3,
+            direction: Direction::Either,
+            allow_equal: false,
+        }
+    }
+}
+
+impl SafetyPolicy {
+    /// Apply a config file on top of the current policy. The file holds one `key = value`
+    /// setting per line; blank lines and lines starting with `#` are ignored.
+    fn load(&mut self, contents: &str) -> Result<(), String> {
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `key = value`", n + 1));
+            };
+
+            self.set(key.trim(), value.trim())
+                .map_err(|e| format!("line {}: {e}", n + 1))?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "min_step" => {
+                self.min_step = value
+                    .parse()
+                    .map_err(|_| format!("invalid min_step: {value}"))?
+            }
+            "max_step" => {
+                self.max_step = value
+                    .parse()
+                    .map_err(|_| format!("invalid max_step: {value}"))?
+            }
+            "direction" => {
+                self.direction = match value {
+                    "increasing" => Direction::Increasing,
+                    "decreasing" => Direction::Decreasing,
+                    "either" => Direction::Either,
+                    _ => return Err(format!("invalid direction: {value}")),
+                }
+            }
+            "allow_equal" => {
+                self.allow_equal = value
+                    .parse()
+                    .map_err(|_| format!("invalid allow_equal: {value}"))?
+            }
+            _ => return Err(format!("unknown setting: {key}")),
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.min_step < 1 || self.min_step > self.max_step {
+            return Err(format!(
+                "step range {}..={} is invalid; need 1 <= min_step <= max_step",
+                self.min_step, self.max_step
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn is_safe(&self, numbers: &[i32]) -> bool {
+        self.explain(numbers).is_none()
+    }
+
+    /// Return the first rule the report breaks, or None if it is safe.
+    fn explain(&self, numbers: &[i32]) -> Option<Violation> {
+        if numbers.len() < 2 {
+            return Some(Violation::TooShort);
+        }
+
+        let mut trend = match self.direction {
+            Direction::Either => None,
+            direction => Some(direction),
+        };
+
+        for i in 1..numbers.len() {
+            let step = numbers[i] - numbers[i - 1];
+
+            if step == 0 {
+                if !self.allow_equal {
+                    return Some(Violation::EqualLevels(i - 1, i));
+                }
+                continue;
+            }
+
+            let direction = if step > 0 {
+                Direction::Increasing
+            } else {
+                Direction::Decreasing
+            };
+
+            match trend {
+                None => trend = Some(direction),
+                Some(t) if t != direction && self.direction == Direction::Either => {
+                    return Some(Violation::DirectionChange(i - 1, i))
+                }
+                Some(t) if t != direction => return Some(Violation::WrongDirection(i - 1, i)),
+                _ => {}
+            }
+
+            if step.abs() > self.max_step {
+                return Some(Violation::StepTooLarge(i - 1, i));
+            } else if step.abs() < self.min_step {
+                return Some(Violation::StepTooSmall(i - 1, i));
+            }
+        }
+
+        None
+    }
+
+    /// Find the fewest levels, at most `k`, whose removal makes the report safe, and return
+    /// their indices. With at most `k` removals, the level kept before any kept level is
+    /// never more than `k + 1` positions back, so this runs in O(n * k^2).
+    fn dampen(&self, numbers: &[i32], k: usize) -> Option<Vec<usize>> {
+        let n = numbers.len();
+        // Removing more levels than the report has is no different from removing all of them.
+        let k = k.min(n);
+        let mut best: Option<(usize, Vec<usize>)> = None;
+
+        for direction in self.directions() {
+            // reach[i][j] is set when level i can be kept with exactly j levels removed before
+            // it, and holds the previously kept level (None when i is the first kept level.)
+            let mut reach: Vec<Vec<Option<Option<usize>>>> = vec![vec![None; k + 1]; n];
+
+            for i in 0..n {
+                if i <= k {
+                    reach[i][i] = Some(None);
+                }
+
+                for p in i.saturating_sub(k + 1)..i {
+                    let gap = i - p - 1;
+                    if !self.step_ok(numbers[p], numbers[i], direction) {
+                        continue;
+                    }
+
+                    for j in 0..=k - gap {
+                        if reach[p][j].is_some() && reach[i][j + gap].is_none() {
+                            reach[i][j + gap] = Some(Some(p));
+                        }
+                    }
+                }
+            }
+
+            // The last kept level needs a predecessor: a single level is never safe.
+            for i in n.saturating_sub(k + 1)..n {
+                for j in 0..=k {
+                    let removed = j + (n - 1 - i);
+                    if removed > k || !matches!(reach[i][j], Some(Some(_))) {
+                        continue;
+                    }
+
+                    if best.as_ref().is_none_or(|(count, _)| removed < *count) {
+                        let mut kept = vec![false; n];
+                        let (mut i, mut j) = (i, j);
+                        while let Some(prev) = reach[i][j] {
+                            kept[i] = true;
+                            let Some(p) = prev else {
+                                break;
+                            };
+                            (i, j) = (p, j - (i - p - 1));
+                        }
+
+                        let indices = (0..n).filter(|x| !kept[*x]).collect();
+                        best = Some((removed, indices));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, indices)| indices)
+    }
+
+    /// Find the fewest level values to change so the report is safe, and return the corrected
+    /// report. The unchanged levels form the longest chain where every gap between two of them
+    /// can be bridged by allowed steps; changed levels are then filled in and checked with
+    /// `is_safe`.
+    fn repair(&self, numbers: &[i32]) -> Option<Vec<i32>> {
+        let n = numbers.len();
+        if n < 2 {
+            return None;
+        }
+
+        let mut best: Option<(usize, Vec<i32>)> = None;
+
+        for direction in self.directions() {
+            let kept = longest_chain(n, |p, i| {
+                self.bridgeable(rise(numbers[p], numbers[i], direction), i - p)
+            });
+
+            let fixed = self.fill(numbers, &kept, direction);
+            if !self.is_safe(&fixed) {
+                continue;
+            }
+
+            let changes = fixed.iter().zip(numbers).filter(|(a, b)| a != b).count();
+            if best.as_ref().is_none_or(|(count, _)| changes < *count) {
+                best = Some((changes, fixed));
+            }
+        }
+
+        best.map(|(_, fixed)| fixed)
+    }
+
+    /// Find the longest subsequence of levels that is safe on its own, as indices into the
+    /// report. Returns an empty list if no two levels make a safe report.
+    fn longest_safe(&self, numbers: &[i32]) -> Vec<usize> {
+        let mut best = vec![];
+
+        for direction in self.directions() {
+            let kept = longest_chain(numbers.len(), |p, i| {
+                self.step_ok(numbers[p], numbers[i], direction)
+            });
+
+            let levels = kept.iter().map(|i| numbers[*i]).collect::<Vec<i32>>();
+            if kept.len() > best.len() && self.is_safe(&levels) {
+                best = kept;
+            }
+        }
+
+        best
+    }
+
+    /// Can a rise (in the direction of travel) be covered by exactly `steps` allowed steps?
+    fn bridgeable(&self, rise: i64, steps: usize) -> bool {
+        let (min, max, steps) = (self.min_step as i64, self.max_step as i64, steps as i64);
+
+        if !self.allow_equal {
+            return steps * min <= rise && rise <= steps * max;
+        }
+
+        // Equal neighbours are allowed, so only some of the steps need to move.
+        rise >= 0 && (rise + max - 1) / max <= steps.min(rise / min)
+    }
+
+    /// Rebuild a report that keeps the levels at the `kept` indices and replaces every other
+    /// level with values that step between them.
+    fn fill(&self, numbers: &[i32], kept: &[usize], direction: Direction) -> Vec<i32> {
+        let sign = match direction {
+            Direction::Decreasing => -1,
+            _ => 1,
+        };
+
+        let mut fixed = numbers.to_vec();
+        let (first, last) = (kept[0], kept[kept.len() - 1]);
+
+        for i in (0..first).rev() {
+            fixed[i] = fixed[i + 1] - sign * self.min_step;
+        }
+
+        for i in last + 1..numbers.len() {
+            fixed[i] = fixed[i - 1] + sign * self.min_step;
+        }
+
+        for pair in kept.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let rise = rise(numbers[from], numbers[to], direction);
+
+            // Spread the rise as evenly as possible over the fewest steps that can carry it.
+            let steps = if self.allow_equal {
+                (rise + self.max_step as i64 - 1) / self.max_step as i64
+            } else {
+                (to - from) as i64
+            };
+
+            for (n, i) in (from + 1..to).enumerate() {
+                let n = n as i64;
+                let step = match steps {
+                    0 => 0,
+                    _ if n < steps => rise / steps + i64::from(n < rise % steps),
+                    _ => 0,
+                };
+                fixed[i] = fixed[i - 1] + sign * step as i32;
+            }
+        }
+
+        fixed
+    }
+
+    fn directions(&self) -> Vec<Direction> {
+        match self.direction {
+            Direction::Either => vec![Direction::Increasing, Direction::Decreasing],
+            direction => vec![direction],
+        }
+    }
+
+    fn step_ok(&self, from: i32, to: i32, direction: Direction) -> bool {
+        let step = match direction {
+            Direction::Increasing => to - from,
+            Direction::Decreasing => from - to,
+            Direction::Either => (to - from).abs(),
+        };
+
+        (step == 0 && self.allow_equal) || (self.min_step..=self.max_step).contains(&step)
+    }
+}
+
+/// Find the longest increasing sequence of indices in `0..n` where each index is linked to the
+/// one before it.
+fn longest_chain(n: usize, linked: impl Fn(usize, usize) -> bool) -> Vec<usize> {
+    // chain[i] is the length of the longest chain ending at i, and the index before i in it.
+    let mut chain: Vec<(usize, Option<usize>)> = vec![(1, None); n];
+    for i in 0..n {
+        for p in 0..i {
+            if chain[p].0 + 1 > chain[i].0 && linked(p, i) {
+                chain[i] = (chain[p].0 + 1, Some(p));
+            }
+        }
+    }
+
+    let Some(mut end) = (0..n).max_by_key(|i| chain[*i].0) else {
+        return vec![];
+    };
+
+    let mut indices = vec![end];
+    while let Some(p) = chain[end].1 {
+        indices.push(p);
+        end = p;
+    }
+    indices.reverse();
+
+    indices
+}
+
+/// How far `to` is from `from` in the given direction of travel.
+fn rise(from: i32, to: i32, direction: Direction) -> i64 {
+    match direction {
+        Direction::Decreasing => from as i64 - to as i64,
+        _ => to as i64 - from as i64,
+    }
+}
 
 fn main() -> Result<(), Error> {
-    let Some(file) = args().nth(1) else {
-        eprintln!("Usage: day2 inputfile");
+    let mut policy = SafetyPolicy::default();
+    let mut tolerance = 1;
+    let mut explain = false;
+    let mut repair = false;
+    let mut longest = false;
+    let mut file = None;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let Some(config) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                if let Err(e) = policy.load(&read_to_string(&config)?) {
+                    eprintln!("{config}: {e}");
+                    exit(1);
+                }
+            }
+            "--min-step" | "--max-step" | "--direction" => {
+                let Some(value) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                if let Err(e) = policy.set(&arg[2..].replace('-', "_"), &value) {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            }
+            "--allow-equal" => policy.allow_equal = true,
+            "--explain" => explain = true,
+            "--repair" => repair = true,
+            "--longest" => longest = true,
+            "--tolerance" => {
+                let Some(Ok(k)) = args.next().map(|x| x.parse()) else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                tolerance = k;
+            }
+            _ if arg.starts_with("--") || file.is_some() => {
+                eprintln!("{USAGE}");
+                exit(1);
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let Some(file) = file else {
+        eprintln!("{USAGE}");
         exit(1);
     };
 
+    if let Err(e) = policy.validate() {
+        eprintln!("Invalid safety policy: {e}");
+        exit(1);
+    }
+
     let contents = read_to_string(file)?;
 
     let mut safe_levels = 0;
     let mut dampener_levels = 0;
+    let mut longest_lengths = BTreeMap::new();
 
     for line in contents.lines() {
         let numbers = line
             .split_whitespace()
-            .filter_map(|candidate| match candidate.parse() {
-                Ok(n) => Some(n),
-                Err(_) => None,
-            })
+            .filter_map(|candidate| candidate.parse().ok())
             .collect::<Vec<i32>>();
 
-        if is_safe(&numbers) {
+        if policy.is_safe(&numbers) {
             safe_levels += 1;
-        } else {
-            for i in 0..numbers.len() {
-                let mut tmp = numbers.clone();
-                tmp.remove(i);
-
-                if is_safe(&tmp) {
-                    dampener_levels += 1;
-                    break;
-                }
+            if longest {
+                *longest_lengths.entry(numbers.len()).or_insert(0) += 1;
             }
+            if explain {
+                println!("{line}: safe");
+            }
+            continue;
         }
-    }
 
-    println!("Total safe levels: {safe_levels}");
-    println!("Total dampened levels: {}", safe_levels + dampener_levels);
+        let removed = policy.dampen(&numbers, tolerance);
+        if removed.is_some() {
+            dampener_levels += 1;
+        }
 
-    Ok(())
-}
+        if let (true, Some(violation)) = (explain, policy.explain(&numbers)) {
+            print!("{line}: unsafe, {violation}");
+            if let Some((a, b)) = violation.pair() {
+                print!(" at {a}..{b} ({} -> {})", numbers[a], numbers[b]);
+            }
+
+            match &removed {
+                Some(indices) => {
+                    let indices = indices
+                        .iter()
+                        .map(|i| format!("index {i} ({})", numbers[*i]))
+                        .collect::<Vec<String>>();
+                    println!("; rescued by removing {}", indices.join(", "));
+                }
+                None => println!("; not rescued"),
+            }
+        }
 
-fn is_safe(numbers: &[i32]) -> bool {
-    let (mut inc, mut dec, mut same, mut toomuch) = (false, false, false, false);
+        if longest {
+            let kept = policy.longest_safe(&numbers);
+            *longest_lengths.entry(kept.len()).or_insert(0) += 1;
 
-    for i in 1..numbers.len() {
-        match (numbers[i], numbers[i - 1]) {
-            _ if numbers[i] > numbers[i - 1] => inc = true,
-            _ if numbers[i] < numbers[i - 1] => dec = true,
-            _ => same = true,
+            if removed.is_none() {
+                let levels = kept
+                    .iter()
+                    .map(|i| numbers[*i].to_string())
+                    .collect::<Vec<String>>();
+                println!(
+                    "{line}: longest safe subsequence has {} of {} levels: {}",
+                    kept.len(),
+                    numbers.len(),
+                    levels.join(" ")
+                );
+            }
+        }
+
+        if repair {
+            match policy.repair(&numbers) {
+                Some(fixed) => {
+                    let changes = fixed.iter().zip(&numbers).filter(|(a, b)| a != b).count();
+                    let fixed = fixed.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+                    println!(
+                        "{line}: repaired with {changes} change(s): {}",
+                        fixed.join(" ")
+                    );
+                }
+                None => println!("{line}: cannot be repaired"),
+            }
         }
+    }
 
-        if (numbers[i] - numbers[i - 1]).abs() > 3 {
-            toomuch = true;
+    println!("Total safe levels: {safe_levels}");
+    println!("Total dampened levels: {}", safe_levels + dampener_levels);
+
+    if longest {
+        println!("Longest safe subsequence lengths:");
+        for (len, count) in longest_lengths {
+            println!("  {len}: {count}");
         }
     }
 
-    matches!(
-        (inc, dec, same, toomuch),
-        (true, false, false, false) | (false, true, false, false)
-    )
+    Ok(())
 }
@@ -19,52 +19,686 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use {std::env::args, std::fs::read_to_string, std::io::Error, std::process::exit};
+use std::{
+    env::args,
+    fmt,
+    fs::{read_to_string, File},
+    io::{stdin, Error, ErrorKind, Read},
+    process::exit,
+};
 
-use regex::Regex;
+const USAGE: &str = "Usage: day3 [--instructions file] \
+                     [--enable-policy global|stack|toggle|region:n] [--trace] inputfile|-";
+
+/// How much memory the scanner reads at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What an instruction does when executed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Semantics {
+    /// Add the product of the operands to the totals.
+    Product,
+    /// Add the sum of the operands to the totals.
+    Sum,
+    /// Add the first operand minus the rest to the totals.
+    Difference,
+    /// Enable the conditional total.
+    Enable,
+    /// Disable the conditional total.
+    Disable,
+    /// Clear both totals.
+    Reset,
+}
+
+impl Semantics {
+    fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            Semantics::Product | Semantics::Sum | Semantics::Difference
+        )
+    }
+}
+
+/// An instruction the lexer recognises, written as `name(a,b,...)` with between `min_arity`
+/// and `max_arity` operands of one to `width` digits each.
+#[derive(Clone, Debug)]
+struct Definition {
+    name: String,
+    min_arity: usize,
+    max_arity: usize,
+    width: usize,
+    semantics: Semantics,
+}
+
+struct InstructionSet(Vec<Definition>);
+
+impl InstructionSet {
+    /// The instructions from the puzzle: `mul(a,b)`, `do()` and `don't()`.
+    fn standard() -> Self {
+        let mut set = InstructionSet(vec![]);
+
+        for (name, arity, semantics) in [
+            ("mul", 2, Semantics::Product),
+            ("do", 0, Semantics::Enable),
+            ("don't", 0, Semantics::Disable),
+        ] {
+            set.define(name, arity, arity, 3, semantics).unwrap();
+        }
+
+        set
+    }
+
+    /// Read an instruction set with one definition per line, in the form
+    /// `name arity width semantics`, where arity is either `n` or `min-max`. Blank lines and
+    /// lines starting with `#` are ignored.
+    fn load(contents: &str) -> Result<Self, String> {
+        let mut set = InstructionSet(vec![]);
+
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let [name, arity, width, semantics] = fields[..] else {
+                return Err(format!(
+                    "line {}: expected `name arity width semantics`",
+                    n + 1
+                ));
+            };
+
+            let (min, max) = arity.split_once('-').unwrap_or((arity, arity));
+            let (Ok(min), Ok(max)) = (min.parse(), max.parse()) else {
+                return Err(format!("line {}: invalid arity: {arity}", n + 1));
+            };
+
+            let Ok(width) = width.parse() else {
+                return Err(format!("line {}: invalid width: {width}", n + 1));
+            };
+
+            let semantics = match semantics {
+                "product" => Semantics::Product,
+                "sum" => Semantics::Sum,
+                "difference" => Semantics::Difference,
+                "enable" => Semantics::Enable,
+                "disable" => Semantics::Disable,
+                "reset" => Semantics::Reset,
+                _ => return Err(format!("line {}: unknown semantics: {semantics}", n + 1)),
+            };
+
+            set.define(name, min, max, width, semantics)
+                .map_err(|e| format!("line {}: {e}", n + 1))?;
+        }
+
+        Ok(set)
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        min_arity: usize,
+        max_arity: usize,
+        width: usize,
+        semantics: Semantics,
+    ) -> Result<(), String> {
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "(),".contains(c)) {
+            return Err(format!("invalid instruction name: {name:?}"));
+        }
+
+        if min_arity > max_arity || max_arity > 8 {
+            return Err(format!("{name}: invalid arity {min_arity}-{max_arity}"));
+        }
+
+        if !(1..=9).contains(&width) {
+            return Err(format!("{name}: operand width must be between 1 and 9"));
+        }
+
+        if semantics.is_arithmetic() && min_arity == 0 {
+            return Err(format!("{name}: {semantics:?} needs at least one operand"));
+        } else if !semantics.is_arithmetic() && max_arity > 0 {
+            return Err(format!("{name}: {semantics:?} takes no operands"));
+        }
+
+        self.0.push(Definition {
+            name: name.to_string(),
+            min_arity,
+            max_arity,
+            width,
+            semantics,
+        });
+
+        Ok(())
+    }
+
+    /// Try to read an instruction at the start of `input`, returning it with its length in
+    /// bytes. Definitions are tried in order, and the first one that matches wins.
+    fn instruction_at(&self, input: &[u8]) -> Option<(Instruction<'_>, usize)> {
+        self.0.iter().find_map(|definition| {
+            let open = definition.name.len();
+            if !input.starts_with(definition.name.as_bytes()) || input.get(open) != Some(&b'(') {
+                return None;
+            }
+
+            let (operands, len) = operand_list(&input[open + 1..], definition)?;
+            let len = open + 1 + len;
+
+            Some((
+                Instruction {
+                    definition,
+                    operands,
+                },
+                len,
+            ))
+        })
+    }
+
+    /// Check whether `input` starts with something that looks like an instruction but isn't
+    /// one, such as `mul(4*`, `mul ( 2 , 4 )` or an operand with too many digits. Only call
+    /// this where `instruction_at` found nothing.
+    fn near_miss_at(&self, input: &[u8]) -> Option<NearMiss> {
+        let input = &input[..input.len().min(self.lookahead())];
+
+        self.0.iter().find_map(|definition| {
+            if !input.starts_with(definition.name.as_bytes()) {
+                return None;
+            }
+
+            let rejected = |len: usize, reason: String| {
+                Some(NearMiss {
+                    text: input[..len.min(input.len())].to_vec(),
+                    reason,
+                })
+            };
+
+            let skip_whitespace = |mut i: usize| {
+                while input.get(i).is_some_and(|c| c.is_ascii_whitespace()) {
+                    i += 1;
+                }
+                i
+            };
+
+            let mut i = skip_whitespace(definition.name.len());
+            let mut whitespace = i > definition.name.len();
+            if input.get(i) != Some(&b'(') {
+                return None;
+            }
+            i += 1;
+
+            let mut operands = 0;
+            loop {
+                let start = i;
+                i = skip_whitespace(i);
+                whitespace |= i > start;
+
+                match input.get(i) {
+                    None => return rejected(i, "unterminated instruction".to_string()),
+                    Some(b')') if operands == 0 => {
+                        i += 1;
+                        break;
+                    }
+                    Some(c) if c.is_ascii_digit() => {
+                        let digits = input[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+                        operands += 1;
+                        i += digits;
+
+                        if digits > definition.width {
+                            return rejected(
+                                i,
+                                format!(
+                                    "operand {operands} has more than {} digits",
+                                    definition.width
+                                ),
+                            );
+                        }
+                    }
+                    Some(c) => {
+                        return rejected(i + 1, format!("unexpected `{}`", c.escape_ascii()))
+                    }
+                }
+
+                let start = i;
+                i = skip_whitespace(i);
+                whitespace |= i > start;
+
+                match input.get(i) {
+                    None => return rejected(i, "unterminated instruction".to_string()),
+                    Some(b',') => i += 1,
+                    Some(b')') => {
+                        i += 1;
+                        break;
+                    }
+                    Some(c) => {
+                        return rejected(i + 1, format!("unexpected `{}`", c.escape_ascii()))
+                    }
+                }
+            }
+
+            if operands < definition.min_arity || operands > definition.max_arity {
+                let arity = match (definition.min_arity, definition.max_arity) {
+                    (min, max) if min == max => format!("{min}"),
+                    (min, max) => format!("{min} to {max}"),
+                };
+                return rejected(i, format!("expected {arity} operands, found {operands}"));
+            }
+
+            if whitespace {
+                return rejected(i, "whitespace inside instruction".to_string());
+            }
+
+            None
+        })
+    }
+
+    /// The length in bytes of the longest instruction in the set.
+    fn longest(&self) -> usize {
+        self.0
+            .iter()
+            .map(|d| d.name.len() + 2 + d.max_arity * (d.width + 1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// How far past a position the scanner needs to see. Near misses can be padded with
+    /// whitespace, so this is further than the longest instruction.
+    fn lookahead(&self) -> usize {
+        self.longest() * 2
+    }
+}
+
+/// Something the scanner found in memory: an instruction, or a fragment that came close.
+#[derive(Clone, Debug)]
+enum Token<'a> {
+    Instruction(Instruction<'a>),
+    NearMiss(NearMiss),
+}
+
+#[derive(Clone, Debug)]
+struct NearMiss {
+    text: Vec<u8>,
+    reason: String,
+}
+
+#[derive(Clone, Debug)]
+struct Instruction<'a> {
+    definition: &'a Definition,
+    operands: Vec<i64>,
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operands = self
+            .operands
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+
+        write!(f, "{}({})", self.definition.name, operands.join(","))
+    }
+}
+
+impl Instruction<'_> {
+    /// The amount an arithmetic instruction adds to the totals.
+    fn value(&self) -> i64 {
+        let mut operands = self.operands.iter();
+
+        match self.definition.semantics {
+            Semantics::Product => operands.fold(1, |acc, x| acc.saturating_mul(*x)),
+            Semantics::Sum => operands.fold(0, |acc, x| acc.saturating_add(*x)),
+            Semantics::Difference => {
+                let first = operands.next().copied().unwrap_or(0);
+                operands.fold(first, |acc, x| acc.saturating_sub(*x))
+            }
+            Semantics::Enable | Semantics::Disable | Semantics::Reset => 0,
+        }
+    }
+}
+
+/// Scans a stream of corrupted memory for instructions in an instruction set, yielding each
+/// one, and each near miss if asked for, with the byte offset it starts at. The stream is
+/// treated as raw bytes, so it need not be text, and anything that isn't a complete instruction
+/// is skipped. The stream is read a chunk at a time, so memory use doesn't depend on its size.
+struct Scanner<'a, R> {
+    set: &'a InstructionSet,
+    lookahead: usize,
+    near_misses: bool,
+    reader: R,
+    buffer: Vec<u8>,
+    /// Offset of the start of `buffer` in the stream.
+    offset: usize,
+    pos: usize,
+    eof: bool,
+}
+
+impl<'a, R: Read> Scanner<'a, R> {
+    fn new(set: &'a InstructionSet, reader: R, near_misses: bool) -> Self {
+        Scanner {
+            set,
+            lookahead: set.lookahead(),
+            near_misses,
+            reader,
+            buffer: vec![],
+            offset: 0,
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Drop everything already scanned and append the next chunk of the stream.
+    fn fill(&mut self) -> Result<(), Error> {
+        self.buffer.drain(..self.pos);
+        self.offset += self.pos;
+        self.pos = 0;
+
+        let len = self.buffer.len();
+        self.buffer.resize(len + CHUNK_SIZE, 0);
+
+        let read = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        self.buffer.truncate(len + read);
+        self.eof = read == 0;
+
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Result<Option<(usize, Token<'a>)>, Error> {
+        loop {
+            // Until the stream ends, only try positions with enough room after them, so an
+            // instruction straddling two chunks is seen whole.
+            while self.pos < self.buffer.len()
+                && (self.eof || self.buffer.len() - self.pos >= self.lookahead)
+            {
+                let start = self.pos;
+                let input = &self.buffer[start..];
+
+                if let Some((instruction, len)) = self.set.instruction_at(input) {
+                    self.pos += len;
+                    return Ok(Some((self.offset + start, Token::Instruction(instruction))));
+                }
+
+                self.pos += 1;
+
+                if !self.near_misses {
+                    continue;
+                }
+
+                if let Some(near_miss) = self.set.near_miss_at(input) {
+                    return Ok(Some((self.offset + start, Token::NearMiss(near_miss))));
+                }
+            }
+
+            if self.eof {
+                return Ok(None);
+            }
+
+            self.fill()?;
+        }
+    }
+}
+
+impl<'a, R: Read> Iterator for Scanner<'a, R> {
+    type Item = Result<(usize, Token<'a>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+/// Read the operands following an instruction's `(`, up to and including the closing `)`.
+/// Returns the operands and the number of bytes read.
+fn operand_list(input: &[u8], definition: &Definition) -> Option<(Vec<i64>, usize)> {
+    let mut operands = vec![];
+    let mut len = 0;
+
+    if input.first() == Some(&b')') {
+        return (definition.min_arity == 0).then_some((operands, 1));
+    }
+
+    while operands.len() < definition.max_arity {
+        let (value, digits) = number(&input[len..], definition.width)?;
+        operands.push(value);
+        len += digits;
+
+        match input.get(len) {
+            Some(b',') => len += 1,
+            Some(b')') if operands.len() >= definition.min_arity => {
+                return Some((operands, len + 1))
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// Read an operand of one to `width` digits, returning its value and length in bytes.
+fn number(input: &[u8], width: usize) -> Option<(i64, usize)> {
+    let digits = input
+        .iter()
+        .take(width)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+
+    if digits == 0 {
+        return None;
+    }
+
+    let value = input[..digits]
+        .iter()
+        .fold(0, |acc, c| acc * 10 + (c - b'0') as i64);
+
+    Some((value, digits))
+}
+
+/// How enable and disable instructions decide whether the conditional total is counting.
+#[derive(Clone, Debug)]
+enum EnableState {
+    /// A single flag: disable turns counting off and enable turns it back on.
+    Global(bool),
+    /// Disable pushes a level and enable pops one. Counting is on when the stack is empty.
+    Stack(usize),
+    /// Every enable or disable instruction flips the flag.
+    Toggle(bool),
+    /// Disable turns counting off for the next `length` instructions; enable ends it early.
+    Region { length: usize, remaining: usize },
+}
+
+impl EnableState {
+    fn parse(policy: &str) -> Result<Self, String> {
+        match policy.split_once(':') {
+            None if policy == "global" => Ok(EnableState::Global(true)),
+            None if policy == "stack" => Ok(EnableState::Stack(0)),
+            None if policy == "toggle" => Ok(EnableState::Toggle(true)),
+            Some(("region", length)) => match length.parse() {
+                Ok(length) => Ok(EnableState::Region {
+                    length,
+                    remaining: 0,
+                }),
+                Err(_) => Err(format!("invalid region length: {length}")),
+            },
+            _ => Err(format!("unknown enable policy: {policy}")),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        match self {
+            EnableState::Global(enabled) | EnableState::Toggle(enabled) => *enabled,
+            EnableState::Stack(depth) => *depth == 0,
+            EnableState::Region { remaining, .. } => *remaining == 0,
+        }
+    }
+
+    fn enable(&mut self) {
+        match self {
+            EnableState::Global(enabled) => *enabled = true,
+            EnableState::Toggle(enabled) => *enabled = !*enabled,
+            EnableState::Stack(depth) => *depth = depth.saturating_sub(1),
+            EnableState::Region { remaining, .. } => *remaining = 0,
+        }
+    }
+
+    fn disable(&mut self) {
+        match self {
+            EnableState::Global(enabled) => *enabled = false,
+            EnableState::Toggle(enabled) => *enabled = !*enabled,
+            EnableState::Stack(depth) => *depth += 1,
+            EnableState::Region { length, remaining } => *remaining = *length,
+        }
+    }
+
+    /// Called after every instruction other than enable and disable.
+    fn step(&mut self) {
+        if let EnableState::Region { remaining, .. } = self {
+            *remaining = remaining.saturating_sub(1);
+        }
+    }
+}
+
+struct Machine {
+    state: EnableState,
+    total: i64,
+    cond_total: i64,
+}
+
+impl Machine {
+    fn new(state: EnableState) -> Self {
+        Machine {
+            state,
+            total: 0,
+            cond_total: 0,
+        }
+    }
+
+    fn execute(&mut self, instruction: &Instruction) {
+        match instruction.definition.semantics {
+            Semantics::Enable => self.state.enable(),
+            Semantics::Disable => self.state.disable(),
+            Semantics::Reset => {
+                self.total = 0;
+                self.cond_total = 0;
+                self.state.step();
+            }
+            Semantics::Product | Semantics::Sum | Semantics::Difference => {
+                let value = instruction.value();
+
+                // Values already saturate, so the totals do too rather than overflowing.
+                self.total = self.total.saturating_add(value);
+
+                if self.state.enabled() {
+                    self.cond_total = self.cond_total.saturating_add(value);
+                }
+
+                self.state.step();
+            }
+        }
+    }
+}
 
 fn main() -> Result<(), Error> {
-    let Some(file) = args().nth(1) else {
-        eprintln!("Usage: day3 inputfile");
-        exit(1);
-    };
+    let mut set = InstructionSet::standard();
+    let mut state = EnableState::Global(true);
+    let mut trace = false;
+    let mut file = None;
 
-    let contents = read_to_string(file)?;
-
-    let re = Regex::new(r"do\(\)|don\'t\(\)|mul\(\d{1,3},\d{1,3}\)").unwrap();
-    let mul_re = Regex::new(r"mul\((\d{1,3}),(\d{1,3})\)").unwrap();
-
-    let mut total = 0;
-    let mut cond_total = 0;
-    let mut multiplying = true;
-    for (whole, []) in re.captures_iter(&contents).map(|c| c.extract()) {
-        match whole {
-            "do()" => multiplying = true,
-            "don't()" => multiplying = false,
-            _ => {
-                let Some((_, [one, two])) = mul_re.captures(whole).map(|c| c.extract()) else {
-                    println!("Invalid mul!");
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--instructions" => {
+                let Some(definitions) = args.next() else {
+                    eprintln!("{USAGE}");
                     exit(1);
                 };
-                let Ok(int_one) = one.parse::<u32>() else {
-                    println!("Invalid 1st int");
-                    exit(1);
+
+                set = match InstructionSet::load(&read_to_string(&definitions)?) {
+                    Ok(set) => set,
+                    Err(e) => {
+                        eprintln!("{definitions}: {e}");
+                        exit(1);
+                    }
                 };
-                let Ok(int_two) = two.parse::<u32>() else {
-                    println!("Invalid 2nd int");
+            }
+            "--enable-policy" => {
+                let Some(policy) = args.next() else {
+                    eprintln!("{USAGE}");
                     exit(1);
                 };
 
-                total += int_one * int_two;
+                state = match EnableState::parse(&policy) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        exit(1);
+                    }
+                };
+            }
+            "--trace" => trace = true,
+            _ if arg.starts_with("--") || file.is_some() => {
+                eprintln!("{USAGE}");
+                exit(1);
+            }
+            _ => file = Some(arg),
+        }
+    }
 
-                if multiplying {
-                    cond_total += int_one * int_two;
+    let Some(file) = file else {
+        eprintln!("{USAGE}");
+        exit(1);
+    };
+
+    let reader: Box<dyn Read> = match file.as_str() {
+        "-" => Box::new(stdin().lock()),
+        _ => Box::new(File::open(file)?),
+    };
+
+    if trace {
+        println!(
+            "{:>10}  {:<8}  {:<20}  {:>10}  {:>12}  {:>12}",
+            "offset", "enabled", "instruction", "value", "total", "conditional"
+        );
+    }
+
+    let mut machine = Machine::new(state);
+    for token in Scanner::new(&set, reader, trace) {
+        match token? {
+            (offset, Token::Instruction(instruction)) => {
+                let enabled = machine.state.enabled();
+                machine.execute(&instruction);
+
+                if trace {
+                    let value = match instruction.definition.semantics.is_arithmetic() {
+                        true => instruction.value().to_string(),
+                        false => "-".to_string(),
+                    };
+
+                    println!(
+                        "{offset:>10}  {:<8}  {:<20}  {value:>10}  {:>12}  {:>12}",
+                        if enabled { "yes" } else { "no" },
+                        instruction.to_string(),
+                        machine.total,
+                        machine.cond_total
+                    );
+                }
+            }
+            (offset, Token::NearMiss(near_miss)) => {
+                if trace {
+                    println!(
+                        "{offset:>10}  {:<8}  {:<20}  {}",
+                        "rejected",
+                        near_miss.text.escape_ascii().to_string(),
+                        near_miss.reason
+                    );
                 }
             }
         }
     }
 
-    println!("Total: {total}");
-    println!("Conditional total: {cond_total}");
+    println!("Total: {}", machine.total);
+    println!("Conditional total: {}", machine.cond_total);
     Ok(())
 }
@@ -19,142 +19,1035 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use {std::env::args, std::fs::read_to_string, std::io::Error, std::process::exit};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env::args,
+    fmt,
+    fs::{read_to_string, write},
+    io::{stdout, Error, IsTerminal},
+    process::exit,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-fn main() -> Result<(), Error> {
-    let Some(file) = args().nth(1) else {
-        eprintln!("Usage: day4 inputfile");
-        exit(1);
-    };
+const USAGE: &str = "Usage: day4 [--words word,... | --words-file file] [--multi] \
+                     [--directions N,NE,...] [--pattern file [--fixed]] [--torus] \
+                     [--render xmas|x-mas [--output file]] inputfile
+       day4 --generate ROWSxCOLS --words word,... [--directions N,NE,...] [--seed n] \
+                     [--output file]";
 
-    let contents = read_to_string(file)?;
+/// How many times the generator starts over before giving up on a puzzle.
+const GENERATE_ATTEMPTS: usize = 1000;
 
-    let mut table = vec![];
-    for line in contents.lines() {
-        table.push(line.chars().collect::<Vec<char>>());
+/// The X-MAS cross from part two: two diagonal MASes sharing their A.
+const X_MAS: &str = "M.S\n.A.\nM.S";
+
+/// A step between neighbouring cells, in layers, rows (down is positive) and columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Direction {
+    layers: isize,
+    rows: isize,
+    cols: isize,
+}
+
+impl Direction {
+    const N: Direction = Direction::planar(-1, 0);
+    const NE: Direction = Direction::planar(-1, 1);
+    const E: Direction = Direction::planar(0, 1);
+    const SE: Direction = Direction::planar(1, 1);
+    const S: Direction = Direction::planar(1, 0);
+    const SW: Direction = Direction::planar(1, -1);
+    const W: Direction = Direction::planar(0, -1);
+    const NW: Direction = Direction::planar(-1, -1);
+
+    /// The eight directions within a layer.
+    const ALL: [Direction; 8] = [
+        Direction::N,
+        Direction::NE,
+        Direction::E,
+        Direction::SE,
+        Direction::S,
+        Direction::SW,
+        Direction::W,
+        Direction::NW,
+    ];
+
+    const fn planar(rows: isize, cols: isize) -> Self {
+        Direction {
+            layers: 0,
+            rows,
+            cols,
+        }
     }
 
-    let rows = table.len();
-    let columns = table[0].len();
+    /// All 26 directions through a stack of layers.
+    fn all_3d() -> Vec<Direction> {
+        let mut directions = vec![];
 
-    let mut total = 0;
-    let mut p2_total = 0;
+        for layers in -1..=1 {
+            for rows in -1..=1 {
+                for cols in -1..=1 {
+                    if (layers, rows, cols) != (0, 0, 0) {
+                        directions.push(Direction { layers, rows, cols });
+                    }
+                }
+            }
+        }
 
-    let check =
-        |a: &char, b: &char, c: &char, d: &char| *a == 'X' && *b == 'M' && *c == 'A' && *d == 'S';
-    let p2_check = |first: &[char; 3], second: &[char; 3], block: &[&[char]; 3]| {
-        block[0][0] == first[0]
-            && block[1][1] == first[1]
-            && block[2][2] == first[2]
-            && block[0][2] == second[0]
-            && block[1][1] == second[1]
-            && block[2][0] == second[2]
-    };
+        directions
+    }
+
+    fn reverse(&self) -> Self {
+        Direction {
+            layers: -self.layers,
+            rows: -self.rows,
+            cols: -self.cols,
+        }
+    }
+
+    /// A compass name like `NE`, prefixed with `U` or `D` when moving up (to the previous
+    /// layer) or down (to the next one.)
+    fn name(&self) -> String {
+        let layer = match self.layers {
+            -1 => "U",
+            1 => "D",
+            _ => "",
+        };
+
+        let planar = match (self.rows, self.cols) {
+            (-1, 0) => "N",
+            (-1, 1) => "NE",
+            (0, 1) => "E",
+            (1, 1) => "SE",
+            (1, 0) => "S",
+            (1, -1) => "SW",
+            (0, -1) => "W",
+            (-1, -1) => "NW",
+            _ => "",
+        };
+
+        format!("{layer}{planar}")
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Direction::all_3d().into_iter().find(|d| d.name() == name)
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Position {
+    layer: usize,
+    row: usize,
+    col: usize,
+}
+
+/// Which matches the renderer highlights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum View {
+    /// Words from the word search, XMAS unless others are given.
+    Words,
+    /// Pattern matches, X-MAS crosses unless another pattern is given.
+    Pattern,
+}
+
+/// What happens at the edges of the grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Topology {
+    /// Words stop at the edges.
+    Bounded,
+    /// Words wrap around to the opposite edge, in every dimension.
+    Toroidal,
+}
+
+/// A word found in the grid, reading from `start` in `direction`.
+#[derive(Clone, Debug, PartialEq)]
+struct Match {
+    word: String,
+    start: Position,
+    direction: Direction,
+}
+
+/// An Aho-Corasick automaton over a list of words, which finds every occurrence of all of them
+/// in a single pass over a line of text.
+struct Automaton {
+    /// Trie transitions out of each state. State 0 is the root.
+    next: Vec<HashMap<char, usize>>,
+    /// The state for the longest proper suffix of each state that is also in the trie.
+    fail: Vec<usize>,
+    /// Words (as indices into the word list) that end at each state, via fail links too.
+    output: Vec<Vec<usize>>,
+    lengths: Vec<usize>,
+}
+
+impl Automaton {
+    fn new(words: &[Vec<char>]) -> Self {
+        let mut next = vec![HashMap::new()];
+        let mut output = vec![vec![]];
+
+        for (i, word) in words.iter().enumerate() {
+            let mut state = 0;
+            for c in word {
+                state = match next[state].get(c) {
+                    Some(s) => *s,
+                    None => {
+                        let child = next.len();
+                        next.push(HashMap::new());
+                        output.push(vec![]);
+                        next[state].insert(*c, child);
+                        child
+                    }
+                };
+            }
+            output[state].push(i);
+        }
+
+        // Fill in fail links breadth first, so a state's fail link is always ready before
+        // its children need it.
+        let mut fail = vec![0; next.len()];
+        let mut queue = next[0].values().copied().collect::<VecDeque<usize>>();
+
+        while let Some(state) = queue.pop_front() {
+            for (c, child) in next[state].clone() {
+                let mut f = fail[state];
+                fail[child] = loop {
+                    match next[f].get(&c) {
+                        Some(s) => break *s,
+                        None if f == 0 => break 0,
+                        None => f = fail[f],
+                    }
+                };
+
+                let inherited = output[fail[child]].clone();
+                output[child].extend(inherited);
+                queue.push_back(child);
+            }
+        }
+
+        Automaton {
+            next,
+            fail,
+            output,
+            lengths: words.iter().map(|w| w.len()).collect(),
+        }
+    }
+
+    /// Find every word in `line`, as (word index, start index) pairs.
+    fn scan(&self, line: &[char]) -> Vec<(usize, usize)> {
+        let mut found = vec![];
+        let mut state = 0;
+
+        for (i, c) in line.iter().enumerate() {
+            state = loop {
+                match self.next[state].get(c) {
+                    Some(s) => break *s,
+                    None if state == 0 => break 0,
+                    None => state = self.fail[state],
+                }
+            };
+
+            for word in self.output[state].iter() {
+                found.push((*word, i + 1 - self.lengths[*word]));
+            }
+        }
+
+        found
+    }
+}
+
+/// A xorshift random number generator, so generated puzzles can be reproduced from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero, so make sure we don't start there.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// A small 2D template to match against the grid, where `None` cells match anything.
+#[derive(Clone, Debug, PartialEq)]
+struct Pattern(Vec<Vec<Option<char>>>);
 
-    for i in 0..rows {
-        for j in 0..columns {
-            // across, forwards
-            if j < columns - 3
-                && check(
-                    &table[i][j],
-                    &table[i][j + 1],
-                    &table[i][j + 2],
-                    &table[i][j + 3],
-                )
-            {
-                total += 1;
-            }
-
-            // across, backwards
-            if j < columns - 3
-                && check(
-                    &table[i][j + 3],
-                    &table[i][j + 2],
-                    &table[i][j + 1],
-                    &table[i][j],
-                )
-            {
-                total += 1;
-            }
-
-            // lateral, forwards
-            if i < rows - 3
-                && check(
-                    &table[i][j],
-                    &table[i + 1][j],
-                    &table[i + 2][j],
-                    &table[i + 3][j],
-                )
-            {
-                total += 1;
-            }
-
-            // lateral, backwards
-            if i < rows - 3
-                && check(
-                    &table[i + 3][j],
-                    &table[i + 2][j],
-                    &table[i + 1][j],
-                    &table[i][j],
-                )
-            {
-                total += 1;
-            }
-
-            if i < rows - 3 && j < columns - 3 {
-                // diagonal, l-r
-                if check(
-                    // top-bottom
-                    &table[i][j],
-                    &table[i + 1][j + 1],
-                    &table[i + 2][j + 2],
-                    &table[i + 3][j + 3],
-                ) || check(
-                    // bottom-top
-                    &table[i + 3][j + 3],
-                    &table[i + 2][j + 2],
-                    &table[i + 1][j + 1],
-                    &table[i][j],
-                ) {
-                    total += 1;
+impl Pattern {
+    /// Parse a template, one row per line, with `.` as the wildcard. Short rows are padded
+    /// with wildcards. A template with nothing but wildcards would match everywhere, so it is
+    /// rejected.
+    fn new(state: &str) -> Result<Self, String> {
+        let mut cells = state
+            .lines()
+            .map(|line| {
+                line.chars()
+                    .map(|c| (c != '.').then_some(c))
+                    .collect::<Vec<Option<char>>>()
+            })
+            .collect::<Vec<Vec<Option<char>>>>();
+
+        let cols = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+        for row in cells.iter_mut() {
+            row.resize(cols, None);
+        }
+
+        if cells.iter().flatten().all(Option::is_none) {
+            return Err("pattern has no letters to match".to_string());
+        }
+
+        Ok(Pattern(cells))
+    }
+
+    fn dimensions(&self) -> (usize, usize) {
+        (self.0.len(), self.0.first().map_or(0, |row| row.len()))
+    }
+
+    /// Turn the pattern a quarter turn clockwise.
+    fn rotate(&self) -> Self {
+        let (rows, cols) = self.dimensions();
+
+        Pattern(
+            (0..cols)
+                .map(|c| (0..rows).rev().map(|r| self.0[r][c]).collect())
+                .collect(),
+        )
+    }
+
+    /// Mirror the pattern left to right.
+    fn reflect(&self) -> Self {
+        Pattern(
+            self.0
+                .iter()
+                .map(|row| row.iter().rev().copied().collect())
+                .collect(),
+        )
+    }
+
+    /// Every distinct rotation and reflection of the pattern, starting with the pattern itself.
+    fn orientations(&self) -> Vec<Pattern> {
+        let mut orientations: Vec<Pattern> = vec![];
+
+        for mut pattern in [self.clone(), self.reflect()] {
+            for _ in 0..4 {
+                if !orientations.contains(&pattern) {
+                    orientations.push(pattern.clone());
                 }
+                pattern = pattern.rotate();
+            }
+        }
 
-                // diagonal, r-l
-                if check(
-                    // top-bottom
-                    &table[i][j + 3],
-                    &table[i + 1][j + 2],
-                    &table[i + 2][j + 1],
-                    &table[i + 3][j],
-                ) || check(
-                    // bottom-top
-                    &table[i + 3][j],
-                    &table[i + 2][j + 1],
-                    &table[i + 1][j + 2],
-                    &table[i][j + 3],
-                ) {
-                    total += 1;
+        orientations
+    }
+}
+
+/// A pattern found in the grid, with the top left corner it was found at and the orientation
+/// (an index into `Pattern::orientations`) that matched.
+#[derive(Clone, Debug, PartialEq)]
+struct PatternMatch {
+    start: Position,
+    orientation: usize,
+}
+
+/// A word search grid: one or more layers of letters, with a blank line between layers.
+struct Grid {
+    cells: Vec<Vec<Vec<char>>>,
+    dimensions: (usize, usize, usize),
+    topology: Topology,
+}
+
+impl Grid {
+    /// Parse a grid, one row per line, with blank lines between layers. Every row must be as
+    /// long as the first, and every layer must have as many rows as the first.
+    fn new(state: &str, topology: Topology) -> Result<Self, String> {
+        let mut cells: Vec<Vec<Vec<char>>> = vec![vec![]];
+        let mut cols = None;
+
+        for (n, line) in state.lines().enumerate() {
+            if !line.trim().is_empty() {
+                let row = line.chars().collect::<Vec<char>>();
+                let expected = *cols.get_or_insert(row.len());
+                if row.len() != expected {
+                    return Err(format!(
+                        "line {}: row has {} cells, expected {expected}",
+                        n + 1,
+                        row.len()
+                    ));
                 }
+
+                cells.last_mut().unwrap().push(row);
+            } else if !cells.last().unwrap().is_empty() {
+                cells.push(vec![]);
             }
+        }
+
+        if cells.len() > 1 && cells.last().unwrap().is_empty() {
+            cells.pop();
+        }
+
+        let rows = cells[0].len();
+        if let Some(layer) = cells.iter().position(|layer| layer.len() != rows) {
+            return Err(format!(
+                "layer {} has {} rows, expected {rows}",
+                layer + 1,
+                cells[layer].len()
+            ));
+        }
+
+        Ok(Grid {
+            dimensions: (cells.len(), rows, cols.unwrap_or(0)),
+            cells,
+            topology,
+        })
+    }
 
-            if i < rows - 2 && j < columns - 2 {
-                let matrix = &[
-                    &table[i][j..j + 3],
-                    &table[i + 1][j..j + 3],
-                    &table[i + 2][j..j + 3],
-                ];
+    /// Build a `rows` by `cols` puzzle with each word in `words` hidden once (list a word twice
+    /// to hide it twice), reading in random `directions`. Words may cross where their letters
+    /// agree, and the remaining cells are filled with decoy letters taken from the words. The
+    /// puzzle is checked with `search`, and only returned if every word appears exactly as
+    /// many times as it was hidden.
+    fn generate(
+        rows: usize,
+        cols: usize,
+        words: &[&str],
+        directions: &[Direction],
+        rng: &mut Rng,
+    ) -> Result<Self, String> {
+        // Empty words can never be placed, and `search` ignores them too.
+        let words = words
+            .iter()
+            .copied()
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<&str>>();
 
-                if p2_check(&['M', 'A', 'S'], &['M', 'A', 'S'], matrix)
-                    || p2_check(&['S', 'A', 'M'], &['S', 'A', 'M'], matrix)
-                    || p2_check(&['M', 'A', 'S'], &['S', 'A', 'M'], matrix)
-                    || p2_check(&['S', 'A', 'M'], &['M', 'A', 'S'], matrix)
-                {
-                    p2_total += 1;
+        // Every copy of a one-letter word's letter is a match, including those in other words
+        // and the decoys, so it can't be hidden a set number of times.
+        if let Some(word) = words.iter().find(|w| w.chars().count() == 1) {
+            return Err(format!(
+                "can't hide the one-letter word {word}, every copy of its letter is a match"
+            ));
+        }
+
+        let mut decoys = words.iter().flat_map(|w| w.chars()).collect::<Vec<char>>();
+        decoys.sort();
+        decoys.dedup();
+
+        if decoys.is_empty() || directions.is_empty() || rows == 0 || cols == 0 {
+            return Err("nothing to generate".to_string());
+        }
+
+        let mut order = words.clone();
+        order.sort_by_key(|w| std::cmp::Reverse(w.chars().count()));
+
+        let mut distinct = order.clone();
+        distinct.sort();
+        distinct.dedup();
+
+        'attempt: for _ in 0..GENERATE_ATTEMPTS {
+            let mut grid = Grid {
+                cells: vec![vec![vec!['\0'; cols]; rows]],
+                dimensions: (1, rows, cols),
+                topology: Topology::Bounded,
+            };
+            let mut expected: HashMap<&str, usize> = HashMap::new();
+
+            for word in order.iter() {
+                let chars = word.chars().collect::<Vec<char>>();
+                let mut placed = None;
+
+                for _ in 0..100 {
+                    let start = Position {
+                        layer: 0,
+                        row: rng.below(rows),
+                        col: rng.below(cols),
+                    };
+                    let direction = directions[rng.below(directions.len())];
+                    let m = Match {
+                        word: word.to_string(),
+                        start,
+                        direction,
+                    };
+
+                    let cells = grid.word_cells(&m);
+                    if cells.len() == chars.len()
+                        && cells
+                            .iter()
+                            .zip(chars.iter())
+                            .all(|(pos, c)| grid.get(*pos).is_some_and(|g| g == '\0' || g == *c))
+                    {
+                        placed = Some((cells, direction));
+                        break;
+                    }
                 }
+
+                let Some((cells, direction)) = placed else {
+                    continue 'attempt;
+                };
+
+                for (pos, c) in cells.iter().zip(chars.iter()) {
+                    grid.cells[0][pos.row][pos.col] = *c;
+                }
+
+                // A palindrome also reads backwards from its last letter.
+                let palindrome = chars.iter().eq(chars.iter().rev());
+                let both_ways = palindrome && directions.contains(&direction.reverse());
+                *expected.entry(word).or_insert(0) += if both_ways { 2 } else { 1 };
             }
+
+            for row in grid.cells[0].iter_mut() {
+                for cell in row.iter_mut().filter(|c| **c == '\0') {
+                    *cell = decoys[rng.below(decoys.len())];
+                }
+            }
+
+            let mut found: HashMap<&str, usize> = HashMap::new();
+            for m in grid.search(&distinct, directions) {
+                let word = distinct.iter().find(|w| **w == m.word).unwrap();
+                *found.entry(word).or_insert(0) += 1;
+            }
+
+            if found == expected {
+                return Ok(grid);
+            }
+        }
+
+        Err(format!(
+            "couldn't place the words with exact counts in {GENERATE_ATTEMPTS} attempts"
+        ))
+    }
+
+    fn get(&self, pos: Position) -> Option<char> {
+        self.cells
+            .get(pos.layer)?
+            .get(pos.row)?
+            .get(pos.col)
+            .copied()
+    }
+
+    fn positions(&self) -> impl Iterator<Item = Position> {
+        let (layers, rows, cols) = self.dimensions;
+
+        (0..layers).flat_map(move |layer| {
+            (0..rows).flat_map(move |row| (0..cols).map(move |col| Position { layer, row, col }))
+        })
+    }
+
+    /// Describe a position as (row, col), or (layer, row, col) in a grid with several layers.
+    fn label(&self, pos: Position) -> String {
+        match self.dimensions.0 {
+            1 => format!("({}, {})", pos.row, pos.col),
+            _ => format!("({}, {}, {})", pos.layer, pos.row, pos.col),
         }
     }
 
+    /// The neighbouring cell in `direction`, if it is on the grid.
+    fn step(&self, pos: Position, direction: Direction) -> Option<Position> {
+        self.offset(pos, direction.layers, direction.rows, direction.cols)
+    }
+
+    /// The cell the given distance away from `pos`, wrapping around the edges of a torus.
+    fn offset(&self, pos: Position, layers: isize, rows: isize, cols: isize) -> Option<Position> {
+        let shift = |x: usize, by: isize, size: usize| match self.topology {
+            Topology::Bounded => x.checked_add_signed(by).filter(|x| *x < size),
+            Topology::Toroidal => Some((x as isize + by).rem_euclid(size as isize) as usize),
+        };
+
+        Some(Position {
+            layer: shift(pos.layer, layers, self.dimensions.0)?,
+            row: shift(pos.row, rows, self.dimensions.1)?,
+            col: shift(pos.col, cols, self.dimensions.2)?,
+        })
+    }
+
+    /// Does `word` read from `start` in `direction`?
+    fn reads(&self, word: &[char], start: Position, direction: Direction) -> bool {
+        let mut pos = Some(start);
+
+        for c in word {
+            let Some(p) = pos else {
+                return false;
+            };
+
+            if self.get(p) != Some(*c) {
+                return false;
+            }
+
+            pos = self.step(p, direction);
+        }
+
+        true
+    }
+
+    /// Find every occurrence of every word reading in any of `directions`.
+    fn search(&self, words: &[&str], directions: &[Direction]) -> Vec<Match> {
+        let words = words
+            .iter()
+            .map(|word| (*word, word.chars().collect::<Vec<char>>()))
+            .filter(|(_, chars)| !chars.is_empty())
+            .collect::<Vec<_>>();
+
+        let mut matches = vec![];
+
+        for start in self.positions() {
+            for direction in directions {
+                for (word, chars) in words.iter() {
+                    // A single letter reads the same in every direction, so only count it once.
+                    if chars.len() == 1 && *direction != directions[0] {
+                        continue;
+                    }
+
+                    if self.reads(chars, start, *direction) {
+                        matches.push(Match {
+                            word: word.to_string(),
+                            start,
+                            direction: *direction,
+                        });
+                    }
+                }
+            }
+        }
+
+        matches
+    }
+
+    /// Every line of cells running in `direction`, in reading order. On a bounded grid these
+    /// run edge to edge; on a torus each line is a cycle, given starting from any of its cells.
+    fn lines(&self, direction: Direction) -> Vec<Vec<Position>> {
+        let mut lines = vec![];
+        let mut seen = HashSet::new();
+
+        for start in self.positions() {
+            let first = match self.topology {
+                Topology::Bounded => self.step(start, direction.reverse()).is_none(),
+                Topology::Toroidal => !seen.contains(&start),
+            };
+
+            if !first {
+                continue;
+            }
+
+            let mut line = vec![];
+            let mut pos = Some(start);
+            while let Some(p) = pos {
+                line.push(p);
+                seen.insert(p);
+                pos = self.step(p, direction).filter(|p| *p != start);
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    /// Find the same matches as `search`, but by running an Aho-Corasick automaton over every
+    /// line of the grid once, rather than trying each word at each cell. This is much faster
+    /// for long word lists.
+    fn search_multi(&self, words: &[&str], directions: &[Direction]) -> Vec<Match> {
+        let words = words
+            .iter()
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+        let automaton = Automaton::new(
+            &words
+                .iter()
+                .map(|word| word.chars().collect())
+                .collect::<Vec<Vec<char>>>(),
+        );
+
+        let longest = words.iter().map(|w| w.chars().count()).max().unwrap_or(0);
+        // As in `search`, a single letter is only counted in the first direction.
+        let single = words
+            .iter()
+            .map(|w| w.chars().count() == 1)
+            .collect::<Vec<bool>>();
+        let mut matches = vec![];
+
+        for direction in directions {
+            for line in self.lines(*direction) {
+                // A cycle on a torus is read round again, so words crossing the seam are seen,
+                // but a word only counts from a start on the first time round.
+                let wrap = match self.topology {
+                    Topology::Bounded => 0,
+                    Topology::Toroidal => longest.saturating_sub(1),
+                };
+
+                let chars = line
+                    .iter()
+                    .cycle()
+                    .take(line.len() + wrap)
+                    .filter_map(|pos| self.get(*pos))
+                    .collect::<Vec<char>>();
+
+                for (word, start) in automaton.scan(&chars) {
+                    if start >= line.len() || (single[word] && *direction != directions[0]) {
+                        continue;
+                    }
+
+                    matches.push(Match {
+                        word: words[word].to_string(),
+                        start: line[start],
+                        direction: *direction,
+                    });
+                }
+            }
+        }
+
+        matches
+    }
+
+    /// The cells a word match covers.
+    fn word_cells(&self, m: &Match) -> Vec<Position> {
+        let mut cells = vec![m.start];
+
+        for _ in 1..m.word.chars().count() {
+            let Some(next) = self.step(cells[cells.len() - 1], m.direction) else {
+                break;
+            };
+            cells.push(next);
+        }
+
+        cells
+    }
+
+    /// The cells under the letters (not the wildcards) of a pattern placed at `start`.
+    fn pattern_cells(&self, pattern: &Pattern, start: Position) -> Vec<Position> {
+        let mut cells = vec![];
+
+        for (r, row) in pattern.0.iter().enumerate() {
+            for (c, cell) in row.iter().enumerate() {
+                if cell.is_none() {
+                    continue;
+                }
+
+                if let Some(pos) = self.offset(start, 0, r as isize, c as isize) {
+                    cells.push(pos);
+                }
+            }
+        }
+
+        cells
+    }
+
+    /// Draw the grid with every cell outside `highlight` shown as `.`, as in the puzzle
+    /// statement. With `colour`, highlighted letters are drawn in bold green and the dots are
+    /// dimmed, using ANSI escapes.
+    fn render(&self, highlight: &HashSet<Position>, colour: bool) -> String {
+        let mut out = String::new();
+
+        for (layer, rows) in self.cells.iter().enumerate() {
+            if layer > 0 {
+                out.push('\n');
+            }
+
+            for (row, cells) in rows.iter().enumerate() {
+                for (col, c) in cells.iter().enumerate() {
+                    let lit = highlight.contains(&Position { layer, row, col });
+
+                    match (lit, colour) {
+                        (true, true) => out.push_str(&format!("\x1b[1;32m{c}\x1b[0m")),
+                        (true, false) => out.push(*c),
+                        (false, true) => out.push_str("\x1b[2m.\x1b[0m"),
+                        (false, false) => out.push('.'),
+                    }
+                }
+                out.push('\n');
+            }
+        }
+
+        out
+    }
+
+    /// Find every place `pattern` fits within a layer of the grid, optionally in any of its
+    /// rotations and reflections as well.
+    fn find(&self, pattern: &Pattern, all_orientations: bool) -> Vec<PatternMatch> {
+        let mut orientations = pattern.orientations();
+        if !all_orientations {
+            orientations.truncate(1);
+        }
+
+        let mut matches = vec![];
+
+        for start in self.positions() {
+            for (orientation, pattern) in orientations.iter().enumerate() {
+                let fits = pattern.0.iter().enumerate().all(|(r, cells)| {
+                    cells.iter().enumerate().all(|(c, cell)| {
+                        let pos = self.offset(start, 0, r as isize, c as isize);
+                        match cell {
+                            None => pos.is_some(),
+                            Some(cell) => pos.and_then(|pos| self.get(pos)) == Some(*cell),
+                        }
+                    })
+                });
+
+                if fits {
+                    matches.push(PatternMatch { start, orientation });
+                }
+            }
+        }
+
+        matches
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let mut words = None;
+    let mut directions = None;
+    let mut topology = Topology::Bounded;
+    let mut multi = false;
+    let mut pattern = None;
+    let mut all_orientations = true;
+    let mut render = None;
+    let mut output = None;
+    let mut generate = None;
+    let mut seed = None;
+    let mut file = None;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--words" => {
+                let Some(list) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                words = Some(
+                    list.split(',')
+                        .map(|w| w.to_string())
+                        .collect::<Vec<String>>(),
+                );
+            }
+            "--words-file" => {
+                let Some(list) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                words = Some(
+                    read_to_string(list)?
+                        .lines()
+                        .map(|w| w.trim().to_string())
+                        .filter(|w| !w.is_empty())
+                        .collect::<Vec<String>>(),
+                );
+            }
+            "--multi" => multi = true,
+            "--directions" => {
+                let Some(list) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                let mut parsed = vec![];
+                for name in list.split(',') {
+                    let Some(direction) = Direction::parse(name) else {
+                        eprintln!("Unknown direction: {name}");
+                        exit(1);
+                    };
+                    parsed.push(direction);
+                }
+                directions = Some(parsed);
+            }
+            "--pattern" => {
+                let Some(template) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+
+                match Pattern::new(&read_to_string(&template)?) {
+                    Ok(parsed) => pattern = Some(parsed),
+                    Err(e) => {
+                        eprintln!("{template}: {e}");
+                        exit(1);
+                    }
+                }
+            }
+            "--fixed" => all_orientations = false,
+            "--torus" => topology = Topology::Toroidal,
+            "--render" => {
+                render = match args.next().as_deref() {
+                    Some("xmas") => Some(View::Words),
+                    Some("x-mas") => Some(View::Pattern),
+                    _ => {
+                        eprintln!("{USAGE}");
+                        exit(1);
+                    }
+                };
+            }
+            "--generate" => {
+                let size = args.next().and_then(|size| {
+                    let (rows, cols) = size.split_once('x')?;
+                    Some((rows.parse::<usize>().ok()?, cols.parse::<usize>().ok()?))
+                });
+
+                let Some(size) = size else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                generate = Some(size);
+            }
+            "--seed" => {
+                let Some(Ok(n)) = args.next().map(|n| n.parse()) else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                seed = Some(n);
+            }
+            "--output" => {
+                let Some(path) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                output = Some(path);
+            }
+            _ if arg.starts_with("--") || file.is_some() => {
+                eprintln!("{USAGE}");
+                exit(1);
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    if let Some((rows, cols)) = generate {
+        let Some(words) = words else {
+            eprintln!("{USAGE}");
+            exit(1);
+        };
+        let words = words.iter().map(|w| w.as_str()).collect::<Vec<&str>>();
+        let directions = directions.unwrap_or(Direction::ALL.to_vec());
+
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+
+        let grid = match Grid::generate(rows, cols, &words, &directions, &mut Rng::new(seed)) {
+            Ok(grid) => grid,
+            Err(e) => {
+                eprintln!("Unable to generate puzzle: {e}");
+                exit(1);
+            }
+        };
+
+        let puzzle = grid.render(&grid.positions().collect(), false);
+        match output {
+            Some(path) => write(path, puzzle)?,
+            None => print!("{puzzle}"),
+        }
+
+        return Ok(());
+    }
+
+    let Some(file) = file else {
+        eprintln!("{USAGE}");
+        exit(1);
+    };
+
+    let contents = read_to_string(&file)?;
+    let grid = match Grid::new(&contents, topology) {
+        Ok(grid) => grid,
+        Err(e) => {
+            eprintln!("{file}: {e}");
+            exit(1);
+        }
+    };
+
+    let directions = directions.unwrap_or_else(|| match grid.dimensions.0 {
+        1 => Direction::ALL.to_vec(),
+        _ => Direction::all_3d(),
+    });
+
+    if let Some(view) = render {
+        let highlight = match view {
+            View::Words => {
+                let words = words.unwrap_or(vec!["XMAS".to_string()]);
+                let words = words.iter().map(|w| w.as_str()).collect::<Vec<&str>>();
+                let matches = match multi {
+                    true => grid.search_multi(&words, &directions),
+                    false => grid.search(&words, &directions),
+                };
+
+                matches
+                    .iter()
+                    .flat_map(|m| grid.word_cells(m))
+                    .collect::<HashSet<Position>>()
+            }
+            View::Pattern => {
+                let pattern = pattern.unwrap_or_else(|| Pattern::new(X_MAS).unwrap());
+                let orientations = pattern.orientations();
+
+                grid.find(&pattern, all_orientations)
+                    .iter()
+                    .flat_map(|m| grid.pattern_cells(&orientations[m.orientation], m.start))
+                    .collect::<HashSet<Position>>()
+            }
+        };
+
+        match output {
+            Some(path) => write(path, grid.render(&highlight, false))?,
+            None => print!("{}", grid.render(&highlight, stdout().is_terminal())),
+        }
+
+        return Ok(());
+    }
+
+    if let Some(words) = words {
+        let words = words.iter().map(|w| w.as_str()).collect::<Vec<&str>>();
+        let matches = match multi {
+            true => grid.search_multi(&words, &directions),
+            false => grid.search(&words, &directions),
+        };
+
+        let mut by_word: HashMap<&str, Vec<&Match>> = HashMap::new();
+        for m in matches.iter() {
+            by_word.entry(m.word.as_str()).or_default().push(m);
+        }
+
+        for word in words.iter() {
+            let Some(found) = by_word.remove(word) else {
+                continue;
+            };
+
+            println!("{word}: {}", found.len());
+            for m in found {
+                println!("  at {} going {}", grid.label(m.start), m.direction);
+            }
+        }
+        println!("Total: {}", matches.len());
+
+        return Ok(());
+    }
+
+    if let Some(pattern) = pattern {
+        let matches = grid.find(&pattern, all_orientations);
+
+        for m in matches.iter() {
+            println!(
+                "Match at {} in orientation {}",
+                grid.label(m.start),
+                m.orientation
+            );
+        }
+        println!("Total: {}", matches.len());
+
+        return Ok(());
+    }
+
+    let total = grid.search(&["XMAS"], &directions).len();
+    let p2_total = grid.find(&Pattern::new(X_MAS).unwrap(), true).len();
+
     println!("Total: {total}");
     println!("Part two total: {p2_total}");
 
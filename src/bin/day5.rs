@@ -19,91 +19,1117 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use {std::env::args, std::fs::read_to_string, std::io::Error, std::process::exit};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    env::args,
+    fmt::{self, Write},
+    fs::read_to_string,
+    io::Error,
+    process::exit,
+};
 
-fn main() -> Result<(), Error> {
-    let Some(file) = args().nth(1) else {
-        eprintln!("Usage: day5 inputfile");
-        exit(1);
-    };
+const USAGE: &str = "Usage: day5 [--dot [--update n [--restrict]] [--reduce]] \
+                     [--report text|json] [--count-orderings] [--moves] [--edits file] inputfile";
 
-    let contents = read_to_string(file)?;
-    let contents = contents.split("\n\n").collect::<Vec<&str>>();
+/// Updates with more pages than this are not counted, since each page needs a bit in the DP mask.
+const MAX_COUNTED_PAGES: usize = 64;
 
-    let (part1, part2) = (contents[0], contents[1]);
+/// Counting gives up once a single step of the DP has to track more sets of pages than this.
+const MAX_COUNTED_STATES: usize = 1 << 16;
 
-    let mut rules = vec![];
-    for line in part1.lines() {
-        let fields = line.split('|').collect::<Vec<&str>>();
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
 
-        rules.push((fields[0], fields[1]));
+impl Format {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
     }
+}
 
-    let mut mid_total = 0;
+/// How far an update is from the nearest valid order.
+struct Distance {
+    moves: usize,
+    swaps: usize,
+    /// Whether `swaps` is the minimum, rather than the swaps needed to reach the corrected order.
+    exact: bool,
+}
 
-    let mut incorrect_pages = vec![];
+/// Why an update was rejected and how it was corrected.
+struct Report {
+    line: usize,
+    pages: Vec<u32>,
+    violations: Vec<(usize, usize)>,
+    corrected: Result<Vec<u32>, Vec<u32>>,
+    distance: Option<Distance>,
+}
+
+impl Report {
+    fn new(order: &PageOrder, line: usize, pages: Vec<u32>) -> Self {
+        let violations = order.violations(&pages);
+        let mut corrected = pages.clone();
+        let corrected = order.fix(&mut corrected).map(|_| corrected);
+
+        Report {
+            line,
+            pages,
+            violations,
+            corrected,
+            distance: None,
+        }
+    }
+
+    /// Works out how many moves and adjacent swaps the correction needs, if it succeeded. Any
+    /// valid order is the nearest one when the rules totally order the update; otherwise a closer
+    /// order may exist than the one found, so the swaps are only an upper bound.
+    fn measure(&mut self, order: &PageOrder) {
+        if let Ok(pages) = &self.corrected {
+            self.distance = Some(Distance {
+                moves: order.moves(&self.pages),
+                swaps: swaps(&self.pages, pages),
+                exact: order.totally_orders(pages),
+            });
+        }
+    }
+
+    fn middle(&self) -> Option<u32> {
+        self.corrected
+            .as_ref()
+            .ok()
+            .map(|pages| pages[pages.len() / 2])
+    }
+
+    fn text(&self) -> String {
+        let mut out = format!("Line {}: {}\n", self.line, list(&self.pages, ","));
+        for (before, after) in &self.violations {
+            let _ = writeln!(
+                out,
+                "  rule {}|{} broken: {} at position {before}, {} at position {after}",
+                self.pages[*before], self.pages[*after], self.pages[*before], self.pages[*after]
+            );
+        }
+
+        match &self.corrected {
+            Ok(pages) => {
+                let _ = writeln!(
+                    out,
+                    "  corrected: {} (middle page {})",
+                    list(pages, ","),
+                    pages[pages.len() / 2]
+                );
+            }
+            Err(pages) => {
+                let _ = writeln!(
+                    out,
+                    "  cannot be corrected, rules form a cycle: {}",
+                    cycle(pages)
+                );
+            }
+        }
+
+        out
+    }
+
+    /// Formats the report as a JSON object, with the distance fields included when `distance`
+    /// is set, as `null` if the update could not be corrected.
+    fn json(&self, distance: bool) -> String {
+        let violations = self
+            .violations
+            .iter()
+            .map(|(before, after)| {
+                format!(
+                    "{{\"before\": {}, \"before_position\": {before}, \
+                     \"after\": {}, \"after_position\": {after}}}",
+                    self.pages[*before], self.pages[*after]
+                )
+            })
+            .collect::<Vec<String>>();
+
+        let (corrected, cycle) = match &self.corrected {
+            Ok(pages) => (format!("[{}]", list(pages, ", ")), "null".to_string()),
+            Err(pages) => ("null".to_string(), format!("[{}]", list(pages, ", "))),
+        };
+
+        let moves = match (&self.distance, distance) {
+            (_, false) => String::new(),
+            (Some(d), true) => format!(
+                ", \"moves\": {}, \"swaps\": {}, \"swaps_exact\": {}",
+                d.moves, d.swaps, d.exact
+            ),
+            (None, true) => ", \"moves\": null, \"swaps\": null, \"swaps_exact\": null".to_string(),
+        };
+
+        format!(
+            "{{\"line\": {}, \"pages\": [{}], \"violations\": [{}], \"corrected\": {corrected}, \
+             \"middle\": {}, \"cycle\": {cycle}{moves}}}",
+            self.line,
+            list(&self.pages, ", "),
+            violations.join(", "),
+            self.middle()
+                .map_or("null".to_string(), |page| page.to_string())
+        )
+    }
+}
+
+/// A change to the rules, or a question about them, read from an edits file.
+#[derive(Clone, Copy)]
+enum Edit {
+    Insert(u32, u32),
+    Remove(u32, u32),
+    Query(u32, u32),
+}
+
+impl Edit {
+    /// Reads one edit per line: `+a|b` adds a rule, `-a|b` removes one, and `?a|b` asks whether
+    /// `a` is required before `b`. Blank lines are ignored.
+    fn load(contents: &str) -> Result<Vec<(usize, Self)>, String> {
+        let mut edits = vec![];
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut chars = line.chars();
+            let edit = match chars.next() {
+                Some('+') => Edit::Insert,
+                Some('-') => Edit::Remove,
+                Some('?') => Edit::Query,
+                _ => {
+                    return Err(format!(
+                        "line {}: expected `+`, `-` or `?` before a rule",
+                        n + 1
+                    ))
+                }
+            };
+
+            let Some((before, after)) = chars.as_str().split_once('|') else {
+                return Err(format!("line {}: expected a rule `before|after`", n + 1));
+            };
+            let before = page(before).map_err(|e| format!("line {}: {e}", n + 1))?;
+            let after = page(after).map_err(|e| format!("line {}: {e}", n + 1))?;
+            if before == after {
+                return Err(format!(
+                    "line {}: rule orders page {before} before itself",
+                    n + 1
+                ));
+            }
+
+            edits.push((n + 1, edit(before, after)));
+        }
+
+        Ok(edits)
+    }
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Edit::Insert(before, after) => write!(f, "+{before}|{after}"),
+            Edit::Remove(before, after) => write!(f, "-{before}|{after}"),
+            Edit::Query(before, after) => write!(f, "?{before}|{after}"),
+        }
+    }
+}
+
+/// Rules that change over time, along with the updates they apply to. Each update's validity is
+/// kept current by re-checking only the updates containing both pages of a changed rule.
+struct RuleStore {
+    order: PageOrder,
+    updates: Vec<(usize, Vec<u32>)>,
+    valid: Vec<bool>,
+    containing: HashMap<u32, Vec<usize>>,
+    acyclic: Option<bool>,
+}
+
+impl RuleStore {
+    fn new(order: PageOrder, updates: Vec<(usize, Vec<u32>)>) -> Self {
+        let mut containing = HashMap::new();
+        for (i, (_, pages)) in updates.iter().enumerate() {
+            for page in pages {
+                containing.entry(*page).or_insert_with(Vec::new).push(i);
+            }
+        }
+
+        RuleStore {
+            valid: updates
+                .iter()
+                .map(|(_, pages)| order.is_ordered(pages))
+                .collect(),
+            order,
+            updates,
+            containing,
+            acyclic: None,
+        }
+    }
+
+    /// Adds a rule, returning the updates whose validity changed. A new rule closes a cycle
+    /// exactly when its pages were already required the other way round.
+    fn insert(&mut self, before: u32, after: u32) -> Vec<usize> {
+        if self.acyclic == Some(true) && self.order.requires(after, before) {
+            self.acyclic = Some(false);
+        }
+
+        if !self.order.insert(before, after) {
+            return vec![];
+        }
+        self.revalidate(before, after)
+    }
+
+    /// Removes a rule, returning the updates whose validity changed. Removing a rule cannot
+    /// create a cycle, but may break the only one, so a cached cycle is forgotten.
+    fn remove(&mut self, before: u32, after: u32) -> Vec<usize> {
+        if !self.order.remove(before, after) {
+            return vec![];
+        }
+
+        if self.acyclic == Some(false) {
+            self.acyclic = None;
+        }
+        self.revalidate(before, after)
+    }
+
+    fn is_acyclic(&mut self) -> bool {
+        *self
+            .acyclic
+            .get_or_insert_with(|| self.order.topological(&self.order.pages()).is_ok())
+    }
+
+    fn revalidate(&mut self, a: u32, b: u32) -> Vec<usize> {
+        let (Some(with_a), Some(with_b)) = (self.containing.get(&a), self.containing.get(&b))
+        else {
+            return vec![];
+        };
+
+        let mut changed = vec![];
+        for i in with_a.iter().filter(|i| with_b.contains(i)) {
+            let valid = self.order.is_ordered(&self.updates[*i].1);
+            if valid != self.valid[*i] {
+                self.valid[*i] = valid;
+                changed.push(*i);
+            }
+        }
+
+        changed
+    }
+}
+
+/// The rules and updates sections of an input, with the line number of each update.
+struct Input {
+    rules: Vec<(u32, u32)>,
+    updates: Vec<(usize, Vec<u32>)>,
+}
+
+impl Input {
+    /// Splits an input into its rules and updates at the first blank line. Line endings may be
+    /// CRLF, and blank lines around either section and whitespace around each line are ignored.
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .skip_while(|(_, line)| line.is_empty());
+
+        // A first section with no `|` anywhere is the updates, not a malformed rules section.
+        let mut section = lines
+            .clone()
+            .take_while(|(_, line)| !line.is_empty())
+            .peekable();
+        if let Some(&(n, _)) = section.peek() {
+            if !section.any(|(_, line)| line.contains('|')) {
+                return Err(format!("line {n}: missing rules section"));
+            }
+        }
+
+        let mut rules = vec![];
+        for (n, line) in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+
+            let Some((before, after)) = line.split_once('|') else {
+                return Err(format!(
+                    "line {n}: expected a rule `before|after`, found `{line}`"
+                ));
+            };
+            let before = page(before).map_err(|e| format!("line {n}: {e}"))?;
+            let after = page(after).map_err(|e| format!("line {n}: {e}"))?;
+            if before == after {
+                return Err(format!("line {n}: rule orders page {before} before itself"));
+            }
+
+            rules.push((before, after));
+        }
+
+        let mut updates = vec![];
+        let mut lines = lines.skip_while(|(_, line)| line.is_empty());
+        while let Some((n, line)) = lines.next() {
+            if line.is_empty() {
+                if let Some((n, _)) = lines.find(|(_, line)| !line.is_empty()) {
+                    return Err(format!("line {n}: unexpected section after the updates"));
+                }
+                break;
+            }
+
+            if line.contains('|') {
+                return Err(format!("line {n}: rule `{line}` found among the updates"));
+            }
+
+            let pages = line
+                .split(',')
+                .map(page)
+                .collect::<Result<Vec<u32>, String>>()
+                .map_err(|e| format!("line {n}: {e}"))?;
+
+            for (i, a) in pages.iter().enumerate() {
+                if let Some(j) = pages[i + 1..].iter().position(|b| b == a) {
+                    return Err(format!(
+                        "line {n}: page {a} appears more than once, at positions {i} and {}",
+                        i + 1 + j
+                    ));
+                }
+            }
+
+            updates.push((n, pages));
+        }
+
+        if updates.is_empty() {
+            return Err("missing updates section".to_string());
+        }
+
+        Ok(Input { rules, updates })
+    }
+}
+
+fn page(field: &str) -> Result<u32, String> {
+    let field = field.trim();
+    if field.is_empty() {
+        return Err("missing page number".to_string());
+    }
+
+    field
+        .parse()
+        .map_err(|_| format!("invalid page number `{field}`"))
+}
+
+/// Page-ordering rules, indexed so any two pages can be compared in constant time.
+struct PageOrder {
+    rules: HashSet<(u32, u32)>,
+    successors: HashMap<u32, Vec<u32>>,
+}
+
+impl PageOrder {
+    fn new(rules: &[(u32, u32)]) -> Self {
+        let mut order = PageOrder {
+            rules: HashSet::new(),
+            successors: HashMap::new(),
+        };
 
-    for update in part2.lines() {
-        let pages = update.split(',').collect::<Vec<&str>>();
+        for (before, after) in rules {
+            order.insert(*before, *after);
+        }
+
+        order
+    }
+
+    /// Adds a rule, returning whether it was new.
+    fn insert(&mut self, before: u32, after: u32) -> bool {
+        if !self.rules.insert((before, after)) {
+            return false;
+        }
 
-        let mut correct = true;
-        for rule in rules.iter() {
-            let (before, after) = rule;
+        self.successors.entry(before).or_default().push(after);
+        true
+    }
+
+    /// Removes a rule, returning whether it was present.
+    fn remove(&mut self, before: u32, after: u32) -> bool {
+        if !self.rules.remove(&(before, after)) {
+            return false;
+        }
 
-            if let Some(index) = find(&pages, after) {
-                if find(&pages, before).is_some() && !pages[0..=index].contains(before) {
-                    correct = false;
+        if let Some(successors) = self.successors.get_mut(&before) {
+            successors.retain(|page| *page != after);
+        }
+        true
+    }
+
+    /// Checks whether the rules require `a` before `b`, directly or through other pages.
+    fn requires(&self, a: u32, b: u32) -> bool {
+        let mut seen = HashSet::from([a]);
+        let mut queue = VecDeque::from([a]);
+        while let Some(page) = queue.pop_front() {
+            for next in self.successors.get(&page).into_iter().flatten() {
+                if *next == b {
+                    return true;
+                }
+                if seen.insert(*next) {
+                    queue.push_back(*next);
                 }
             }
         }
 
-        let mid_idx = pages.len() / 2;
-        if correct {
-            mid_total += pages[mid_idx].parse::<u32>().unwrap();
+        false
+    }
+
+    /// Orders two pages by the rule between them. Pages with no direct rule compare equal, and a
+    /// pair with rules both ways compares greater in either order, so neither order passes.
+    fn compare(&self, a: u32, b: u32) -> Ordering {
+        if a != b && self.rules.contains(&(b, a)) {
+            Ordering::Greater
+        } else if a != b && self.rules.contains(&(a, b)) {
+            Ordering::Less
         } else {
-            incorrect_pages.push(update);
+            Ordering::Equal
         }
     }
 
-    println!("Part 1 Total: {mid_total}");
+    /// Checks an update against every rule between its pages, with O(pages^2) rule lookups.
+    /// Comparing only neighbouring pages would miss a rule broken across pages that have no rule
+    /// between them, and an update can be subject to a rule for every pair, so each pair is
+    /// checked.
+    fn is_ordered(&self, pages: &[u32]) -> bool {
+        pages
+            .iter()
+            .enumerate()
+            .all(|(i, a)| pages[i + 1..].iter().all(|b| self.compare(*a, *b).is_le()))
+    }
 
-    let mut corrected_total = 0;
-    for update in incorrect_pages {
-        let mut pages = update.split(',').collect::<Vec<&str>>();
+    /// Counts the orderings of an update that satisfy every rule between its pages, by building
+    /// them up one page at a time over the sets of pages that can come first. Returns `None` when
+    /// the update is too large or too loosely constrained to count within the limits.
+    fn orderings(&self, pages: &[u32]) -> Option<u128> {
+        if pages.len() > MAX_COUNTED_PAGES {
+            return None;
+        }
 
-        let mut i = 0;
-        loop {
-            for (before, after) in rules.iter() {
-                let Some(after_index) = find(&pages, after) else {
-                    continue;
+        let mut required = vec![0u64; pages.len()];
+        for (i, a) in pages.iter().enumerate() {
+            for (j, b) in pages.iter().enumerate() {
+                if self.rules.contains(&(*b, *a)) {
+                    required[i] |= 1 << j;
+                }
+            }
+        }
+
+        let mut counts = HashMap::from([(0u64, 1u128)]);
+        for _ in 0..pages.len() {
+            let mut next = HashMap::new();
+            for (placed, count) in counts {
+                for (i, required) in required.iter().enumerate() {
+                    if placed & (1 << i) == 0 && placed & required == *required {
+                        let total = next.entry(placed | (1 << i)).or_insert(0u128);
+                        *total = total.checked_add(count)?;
+                    }
+                }
+            }
+
+            if next.len() > MAX_COUNTED_STATES {
+                return None;
+            }
+            counts = next;
+        }
+
+        Some(counts.into_values().sum())
+    }
+
+    /// Works out, for each pair of positions in an update, whether the rules between its pages
+    /// require the first page somewhere before the second.
+    fn requirements(&self, pages: &[u32]) -> Vec<Vec<bool>> {
+        let mut required = pages
+            .iter()
+            .map(|a| {
+                pages
+                    .iter()
+                    .map(|b| self.rules.contains(&(*a, *b)))
+                    .collect()
+            })
+            .collect::<Vec<Vec<bool>>>();
+
+        for k in 0..pages.len() {
+            for i in 0..pages.len() {
+                for j in 0..pages.len() {
+                    required[i][j] |= required[i][k] && required[k][j];
+                }
+            }
+        }
+
+        required
+    }
+
+    /// The fewest pages that must be taken out and reinserted to make an update valid. The pages
+    /// left in place must not include a pair the rules require the other way round, and such
+    /// conflicts form a partial order, so the most that can stay is its largest antichain. By
+    /// Dilworth's theorem the number moved is then the size of a maximum matching between pages
+    /// and the later pages that must come before them.
+    fn moves(&self, pages: &[u32]) -> usize {
+        let required = self.requirements(pages);
+        let conflicts = (0..pages.len())
+            .map(|i| {
+                (i + 1..pages.len())
+                    .filter(|j| required[*j][i])
+                    .collect::<Vec<usize>>()
+            })
+            .collect::<Vec<Vec<usize>>>();
+
+        let mut matched = vec![None; pages.len()];
+        (0..pages.len())
+            .filter(|i| augment(*i, &conflicts, &mut matched, &mut vec![false; pages.len()]))
+            .count()
+    }
+
+    /// Lists the positions of each pair of pages in an update that breaks a rule, as the
+    /// position of the page that should come first followed by the page that should come second.
+    fn violations(&self, pages: &[u32]) -> Vec<(usize, usize)> {
+        let mut violations = vec![];
+        for i in 0..pages.len() {
+            for j in i + 1..pages.len() {
+                if self.compare(pages[i], pages[j]).is_gt() {
+                    violations.push((j, i));
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// Every page named by a rule, in ascending order.
+    fn pages(&self) -> Vec<u32> {
+        self.rules
+            .iter()
+            .flat_map(|(before, after)| [*before, *after])
+            .collect::<BTreeSet<u32>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The rules between the given pages, in ascending order.
+    fn edges(&self, pages: &[u32]) -> Vec<(u32, u32)> {
+        let pages = pages.iter().collect::<HashSet<&u32>>();
+        let mut edges = self
+            .rules
+            .iter()
+            .filter(|(before, after)| pages.contains(before) && pages.contains(after))
+            .copied()
+            .collect::<Vec<(u32, u32)>>();
+        edges.sort();
+        edges
+    }
+
+    /// Checks whether the rules give every pair of pages in an update a consistent order, which
+    /// is exactly when each page precedes a different number of the others.
+    fn totally_orders(&self, pages: &[u32]) -> bool {
+        let mut scores = vec![false; pages.len()];
+        for a in pages {
+            let mut score = 0;
+            for b in pages.iter().filter(|b| *b != a) {
+                match self.compare(*a, *b) {
+                    Ordering::Less => score += 1,
+                    Ordering::Greater => {}
+                    Ordering::Equal => return false,
+                }
+            }
+
+            if scores[score] {
+                return false;
+            }
+            scores[score] = true;
+        }
+
+        true
+    }
+
+    /// Puts an update into an order that satisfies the rules. Updates the rules totally order are
+    /// sorted with the comparator directly; anything else falls back to a topological sort, which
+    /// returns the pages on a cycle if there is one. Checking for a total order takes O(pages^2)
+    /// rule lookups, which dominates the O(pages log pages) sort.
+    fn fix(&self, pages: &mut [u32]) -> Result<(), Vec<u32>> {
+        if self.totally_orders(pages) {
+            pages.sort_by(|a, b| self.compare(*a, *b));
+        } else {
+            let order = self.topological(pages)?;
+            pages.copy_from_slice(&order);
+        }
+
+        Ok(())
+    }
+
+    /// Topologically sorts the pages of an update using only the rules between them. Pages that
+    /// are not constrained relative to each other keep their original order. If the rules form a
+    /// cycle, the pages on it are returned in rule order instead.
+    fn topological(&self, pages: &[u32]) -> Result<Vec<u32>, Vec<u32>> {
+        let index = pages
+            .iter()
+            .enumerate()
+            .map(|(i, page)| (*page, i))
+            .collect::<HashMap<u32, usize>>();
+
+        let mut successors = vec![vec![]; pages.len()];
+        let mut predecessors = vec![vec![]; pages.len()];
+        for (i, page) in pages.iter().enumerate() {
+            for after in self.successors.get(page).into_iter().flatten() {
+                if let Some(&j) = index.get(after) {
+                    successors[i].push(j);
+                    predecessors[j].push(i);
+                }
+            }
+        }
+
+        let mut in_degree = predecessors.iter().map(Vec::len).collect::<Vec<usize>>();
+        let mut ready = (0..pages.len())
+            .filter(|i| in_degree[*i] == 0)
+            .collect::<BTreeSet<usize>>();
+
+        let mut order = vec![];
+        while let Some(i) = ready.pop_first() {
+            order.push(pages[i]);
+            for &j in &successors[i] {
+                in_degree[j] -= 1;
+                if in_degree[j] == 0 {
+                    ready.insert(j);
+                }
+            }
+        }
+
+        if order.len() == pages.len() {
+            return Ok(order);
+        }
+
+        // Every page left unsorted still has an unsorted predecessor, so walking predecessors from
+        // any of them must eventually revisit a page.
+        let mut seen = vec![None; pages.len()];
+        let mut path = vec![];
+        let mut i = (0..pages.len()).find(|i| in_degree[*i] > 0).unwrap();
+        while seen[i].is_none() {
+            seen[i] = Some(path.len());
+            path.push(i);
+            i = *predecessors[i].iter().find(|j| in_degree[**j] > 0).unwrap();
+        }
+
+        Err(path[seen[i].unwrap()..]
+            .iter()
+            .rev()
+            .map(|i| pages[*i])
+            .collect())
+    }
+}
+
+/// Removes every edge implied by a longer path, apart from those in `keep`. `pages` must list
+/// every page in topological order. An edge is implied when its target can be reached from
+/// another successor of its source, and that successor must come earlier in topological order,
+/// so the successors are searched in order and each search stops past the last of them.
+fn reduce(pages: &[u32], edges: &[(u32, u32)], keep: &HashSet<(u32, u32)>) -> Vec<(u32, u32)> {
+    let position = pages
+        .iter()
+        .enumerate()
+        .map(|(i, page)| (*page, i))
+        .collect::<HashMap<u32, usize>>();
+
+    let mut successors = vec![vec![]; pages.len()];
+    for (before, after) in edges {
+        successors[position[before]].push(position[after]);
+    }
+    for next in successors.iter_mut() {
+        next.sort();
+    }
+
+    // seen[i] holds the last source whose search reached page i.
+    let mut seen = vec![usize::MAX; pages.len()];
+    let mut implied = HashSet::new();
+    for (source, next) in successors.iter().enumerate() {
+        let Some(&last) = next.last() else {
+            continue;
+        };
+
+        for &target in next {
+            if seen[target] == source {
+                implied.insert((pages[source], pages[target]));
+                continue;
+            }
+
+            seen[target] = source;
+            let mut stack = vec![target];
+            while let Some(page) = stack.pop() {
+                for &after in successors[page].iter().take_while(|after| **after <= last) {
+                    if seen[after] != source {
+                        seen[after] = source;
+                        stack.push(after);
+                    }
+                }
+            }
+        }
+    }
+
+    edges
+        .iter()
+        .filter(|edge| keep.contains(edge) || !implied.contains(edge))
+        .copied()
+        .collect()
+}
+
+/// Looks for an augmenting path from `i` in a bipartite matching, using Kuhn's algorithm.
+fn augment(
+    i: usize,
+    edges: &[Vec<usize>],
+    matched: &mut [Option<usize>],
+    visited: &mut [bool],
+) -> bool {
+    for j in &edges[i] {
+        if visited[*j] {
+            continue;
+        }
+        visited[*j] = true;
+
+        if matched[*j].is_none_or(|k| augment(k, edges, matched, visited)) {
+            matched[*j] = Some(i);
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Counts the adjacent swaps that turn `pages` into `target`, which is the number of pairs the two
+/// orderings disagree on.
+fn swaps(pages: &[u32], target: &[u32]) -> usize {
+    let rank = target
+        .iter()
+        .enumerate()
+        .map(|(i, page)| (*page, i))
+        .collect::<HashMap<u32, usize>>();
+
+    (0..pages.len())
+        .map(|i| {
+            (i + 1..pages.len())
+                .filter(|j| rank[&pages[i]] > rank[&pages[*j]])
+                .count()
+        })
+        .sum()
+}
+
+fn plural<T: fmt::Display + PartialEq + From<u8>>(n: T, noun: &str) -> String {
+    if n == T::from(1) {
+        format!("{n} {noun}")
+    } else {
+        format!("{n} {noun}s")
+    }
+}
+
+fn list(pages: &[u32], separator: &str) -> String {
+    pages
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// Formats the pages on a cycle, repeating the first page to close it.
+fn cycle(pages: &[u32]) -> String {
+    format!("{} -> {}", list(pages, " -> "), pages[0])
+}
+
+/// Renders the rules between `pages` as a Graphviz digraph, drawing `violated` edges in red.
+fn dot(pages: &[u32], edges: &[(u32, u32)], violated: &HashSet<(u32, u32)>) -> String {
+    let mut out = String::from("digraph rules {\n");
+    for page in pages {
+        let _ = writeln!(out, "    {page};");
+    }
+
+    for edge @ (before, after) in edges {
+        if violated.contains(edge) {
+            let _ = writeln!(out, "    {before} -> {after} [color=red, penwidth=2];");
+        } else {
+            let _ = writeln!(out, "    {before} -> {after};");
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+fn main() -> Result<(), Error> {
+    let mut graph = false;
+    let mut selected = None;
+    let mut restrict = false;
+    let mut reduced = false;
+    let mut report = None;
+    let mut count = false;
+    let mut moves = false;
+    let mut edits = None;
+    let mut file = None;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dot" => graph = true,
+            "--update" => {
+                let Some(Ok(n)) = args.next().map(|x| x.parse::<usize>()) else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                selected = Some(n);
+            }
+            "--restrict" => restrict = true,
+            "--reduce" => reduced = true,
+            "--count-orderings" => count = true,
+            "--moves" => moves = true,
+            "--edits" => {
+                let Some(file) = args.next() else {
+                    eprintln!("{USAGE}");
+                    exit(1);
                 };
+                edits = Some(file);
+            }
+            "--report" => {
+                let Some(format) = args.next().and_then(|x| Format::parse(&x)) else {
+                    eprintln!("{USAGE}");
+                    exit(1);
+                };
+                report = Some(format);
+            }
+            _ if arg.starts_with("--") || file.is_some() => {
+                eprintln!("{USAGE}");
+                exit(1);
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let Some(file) = file else {
+        eprintln!("{USAGE}");
+        exit(1);
+    };
 
-                let Some(before_index) = find(&pages, before) else {
+    let input = match Input::parse(&read_to_string(&file)?) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{file}: {e}");
+            exit(1);
+        }
+    };
+
+    let mut order = PageOrder::new(&input.rules);
+    let mut updates = input.updates;
+
+    if let Some(edits) = edits {
+        let changes = match Edit::load(&read_to_string(&edits)?) {
+            Ok(changes) => changes,
+            Err(e) => {
+                eprintln!("{edits}: {e}");
+                exit(1);
+            }
+        };
+
+        let mut store = RuleStore::new(order, updates);
+        for (n, edit) in changes {
+            let changed = match edit {
+                Edit::Insert(before, after) => store.insert(before, after),
+                Edit::Remove(before, after) => store.remove(before, after),
+                Edit::Query(before, after) => {
+                    let answer = if store.order.requires(before, after) {
+                        "is"
+                    } else {
+                        "is not"
+                    };
+                    println!("Edit {n}: {before} {answer} required before {after}");
                     continue;
-                };
+                }
+            };
 
-                if !pages[0..=after_index].contains(before) {
-                    (pages[before_index], pages[after_index]) =
-                        (pages[after_index], pages[before_index]);
-                    i = 0;
+            let acyclic = if store.is_acyclic() {
+                "acyclic"
+            } else {
+                "cyclic"
+            };
+            println!("Edit {n}: {edit}, rules are {acyclic}");
+            for i in changed {
+                let (line, _) = store.updates[i];
+                let valid = if store.valid[i] { "valid" } else { "invalid" };
+                println!("  update on line {line} is now {valid}");
+            }
+        }
+
+        (order, updates) = (store.order, store.updates);
+    }
+
+    if graph {
+        let update = match selected {
+            Some(n) if n >= 1 && n <= updates.len() => Some(&updates[n - 1].1),
+            Some(n) => {
+                eprintln!("There is no update {n}, the input has {}", updates.len());
+                exit(1);
+            }
+            None => None,
+        };
+
+        let pages = match update {
+            Some(pages) if restrict => pages.clone(),
+            _ => order.pages(),
+        };
+
+        let violated = update
+            .map(|pages| {
+                order
+                    .violations(pages)
+                    .into_iter()
+                    .map(|(before, after)| (pages[before], pages[after]))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut edges = order.edges(&pages);
+        if reduced {
+            let sorted = match order.topological(&pages) {
+                Ok(sorted) => sorted,
+                Err(pages) => {
+                    eprintln!(
+                        "Cannot reduce the graph, rules form a cycle: {}",
+                        cycle(&pages)
+                    );
+                    exit(1);
                 }
+            };
+
+            edges = reduce(&sorted, &edges, &violated);
+        }
+
+        print!("{}", dot(&pages, &edges, &violated));
+        return Ok(());
+    }
+
+    let mut counts = vec![];
+    let mut orderings = BTreeMap::new();
+    let mut uncounted = 0;
+    if count {
+        for (line, pages) in &updates {
+            let n = order.orderings(pages);
+            match n {
+                Some(n) => *orderings.entry(n).or_insert(0) += 1,
+                None => uncounted += 1,
             }
 
-            if i == pages.len() {
-                break;
+            if report != Some(Format::Json) {
+                match n {
+                    Some(n) => println!("Line {line}: {}", plural(n, "valid ordering")),
+                    None => println!("Line {line}: too many valid orderings to count"),
+                }
             }
+            counts.push((*line, n));
+        }
+    }
 
-            i += 1;
+    let mut mid_total = 0;
+
+    let mut incorrect_pages = vec![];
+
+    for (line, pages) in updates {
+        if order.is_ordered(&pages) {
+            mid_total += pages[pages.len() / 2];
+        } else {
+            incorrect_pages.push((line, pages));
         }
+    }
+
+    let mut corrected_total = 0;
+    let mut total_moves = 0;
+    let mut reports = vec![];
+    for (line, pages) in incorrect_pages {
+        let mut update = Report::new(&order, line, pages);
+        match &update.corrected {
+            Ok(pages) => {
+                corrected_total += pages[pages.len() / 2];
 
-        let mid_idx = pages.len() / 2;
-        corrected_total += pages[mid_idx].parse::<u32>().unwrap();
+                if moves {
+                    update.measure(&order);
+                }
+
+                if let (Some(d), true) = (&update.distance, report != Some(Format::Json)) {
+                    let bound = if d.exact { "" } else { "at most " };
+                    println!(
+                        "Line {line}: {}, {bound}{}",
+                        plural(d.moves, "move"),
+                        plural(d.swaps, "adjacent swap")
+                    );
+                }
+                total_moves += update.distance.as_ref().map_or(0, |d| d.moves);
+            }
+            Err(pages) if report.is_none() => eprintln!(
+                "Update on line {line} cannot be ordered, rules form a cycle: {}",
+                cycle(pages)
+            ),
+            Err(_) => {}
+        }
+        reports.push(update);
+    }
+
+    match report {
+        Some(Format::Json) => {
+            let reports = reports
+                .iter()
+                .map(|update| update.json(moves))
+                .collect::<Vec<String>>();
+            let mut extra = String::new();
+            if moves {
+                let _ = write!(extra, ", \"total_moves\": {total_moves}");
+            }
+            if count {
+                let counts = counts
+                    .iter()
+                    .map(|(line, n)| {
+                        let n = n.map_or("null".to_string(), |n| n.to_string());
+                        format!("{{\"line\": {line}, \"orderings\": {n}}}")
+                    })
+                    .collect::<Vec<String>>();
+                let histogram = orderings
+                    .iter()
+                    .map(|(n, updates)| format!("\"{n}\": {updates}"))
+                    .collect::<Vec<String>>();
+                let _ = write!(
+                    extra,
+                    ", \"orderings\": [{}], \"orderings_histogram\": {{{}}}, \
+                     \"uncounted\": {uncounted}",
+                    counts.join(", "),
+                    histogram.join(", ")
+                );
+            }
+
+            println!(
+                "{{\"updates\": [{}], \"part1_total\": {mid_total}, \
+                 \"corrected_total\": {corrected_total}{extra}}}",
+                reports.join(", ")
+            );
+            return Ok(());
+        }
+        Some(Format::Text) => {
+            for update in &reports {
+                print!("{}", update.text());
+            }
+        }
+        None => {}
     }
 
+    println!("Part 1 Total: {mid_total}");
     println!("Corrected total: {corrected_total}");
-    Ok(())
-}
+    if moves {
+        println!("Total moves: {total_moves}");
+    }
 
-fn find(haystack: &Vec<&str>, needle: &str) -> Option<usize> {
-    haystack.iter().position(|x| *x == needle)
+    if count {
+        println!("Valid orderings per update:");
+        for (n, updates) in orderings {
+            println!("  {n}: {updates}");
+        }
+        if uncounted > 0 {
+            println!("  uncounted: {uncounted}");
+        }
+    }
+
+    Ok(())
 }